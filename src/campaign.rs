@@ -0,0 +1,102 @@
+//! Campaign data that is kept between sessions. It is stored as plain text,
+//! one entry per line:
+//!
+//! ```text
+//! macro greatsword = 2d6+4
+//! macro sneak_attack = 3d6
+//! ```
+//!
+//! Blank lines are ignored.
+
+use crate::dice;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Campaign {
+    /// Dice expressions by name, rolled as `#name`.
+    pub macros: BTreeMap<String, String>,
+}
+
+/// Reads the campaign stored at `path`. A missing file is an empty campaign.
+pub fn load(path: &Path) -> Result<Campaign, String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => parse(&contents).map_err(|err| format!("{}: {}", path.display(), err)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Campaign::default()),
+        Err(err) => Err(format!("{}: {}", path.display(), err)),
+    }
+}
+
+pub fn save(path: &Path, campaign: &Campaign) -> io::Result<()> {
+    fs::write(path, campaign.to_string())
+}
+
+fn parse(contents: &str) -> Result<Campaign, String> {
+    let mut campaign = Campaign::default();
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let definition = line
+            .strip_prefix("macro ")
+            .ok_or_else(|| format!("line {}: unknown entry", i + 1))?;
+        let (name, expr) = dice::parse_definition(definition, '#')
+            .map_err(|err| format!("line {}: {}", i + 1, err))?;
+        campaign.macros.insert(name, expr);
+    }
+
+    Ok(campaign)
+}
+
+/// Formats the campaign the way it is stored.
+impl fmt::Display for Campaign {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, expr) in &self.macros {
+            writeln!(f, "macro {} = {}", name, expr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut campaign = Campaign::default();
+        campaign
+            .macros
+            .insert("greatsword".to_string(), "2d6+4".to_string());
+        campaign
+            .macros
+            .insert("sneak_attack".to_string(), "3d6".to_string());
+
+        let contents = campaign.to_string();
+        assert_eq!(
+            contents,
+            "macro greatsword = 2d6+4\nmacro sneak_attack = 3d6\n"
+        );
+        assert_eq!(parse(&contents), Ok(campaign));
+    }
+
+    #[test]
+    fn blank_lines() {
+        let campaign = parse("\n  macro axe = 1d12 + 3\n\n").unwrap();
+        assert_eq!(campaign.macros["axe"], "1d12 + 3");
+    }
+
+    #[test]
+    fn unknown_entry() {
+        assert_eq!(
+            parse("macro axe = 1d12\nspell fireball = 8d6"),
+            Err("line 2: unknown entry".to_string())
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Conditions such as `dazed` or `prone` that affect a character, possibly
+//! for a number of rounds. They are entered as a name followed by any of:
+//!
+//! ```text
+//! dazed 2 start save from Wizard
+//! ```
+//!
+//! - a number of rounds, counted down at the end of the character's turns,
+//! - `start` to count down at the start of the turns instead (`end` is the
+//!   default),
+//! - `save` if a saving throw at the end of each turn ends it,
+//! - `from` and the source of the condition, which takes the rest of the
+//!   line.
+
+use crate::state::Character;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub name: String,
+    /// Who or what caused the condition.
+    pub source: Option<String>,
+    /// The rounds left, or `None` if it lasts until removed.
+    pub rounds: Option<u32>,
+    /// When during the character's turn the rounds are counted down.
+    pub ends: TurnPoint,
+    /// Whether a successful saving throw at the end of a turn ends it.
+    pub save_ends: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TurnPoint {
+    Start,
+    End,
+}
+
+impl Condition {
+    pub fn new(name: &str) -> Self {
+        Condition {
+            name: name.to_string(),
+            source: None,
+            rounds: None,
+            ends: TurnPoint::End,
+            save_ends: false,
+        }
+    }
+}
+
+/// Parses a condition as described in the [module documentation](self).
+pub fn parse(input: &str) -> Result<Condition, String> {
+    let (input, source) = match input.find(" from ") {
+        Some(i) => (&input[..i], Some(input[i + 6..].trim().to_string())),
+        None => (input, None),
+    };
+    let mut condition = Condition::new("");
+    let mut name = vec![];
+
+    for word in input.split_whitespace() {
+        match word {
+            "start" => condition.ends = TurnPoint::Start,
+            "end" => condition.ends = TurnPoint::End,
+            "save" => condition.save_ends = true,
+            _ => match word.parse() {
+                Ok(_) if condition.rounds.is_some() => {
+                    return Err(format!("more than one duration: '{}'", word))
+                }
+                Ok(0) => return Err("a condition must last at least 1 round".to_string()),
+                Ok(rounds) => condition.rounds = Some(rounds),
+                Err(_) => name.push(word),
+            },
+        }
+    }
+
+    if name.is_empty() {
+        return Err("expected the name of the condition".to_string());
+    }
+    condition.name = name.join(" ");
+    condition.source = source.filter(|source| !source.is_empty());
+    Ok(condition)
+}
+
+/// Formats the condition for the character list, with the rounds left and
+/// whether a save ends it, e.g. `dazed (2, save)`.
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        match (self.rounds, self.save_ends) {
+            (Some(rounds), true) => write!(f, " ({}, save)", rounds),
+            (Some(rounds), false) => write!(f, " ({})", rounds),
+            (None, true) => write!(f, " (save)"),
+            (None, false) => Ok(()),
+        }
+    }
+}
+
+/// Counts down the conditions of the character at a point of its turn,
+/// removing the ones that run out. Describes what happened, for the log.
+pub fn tick(character: &mut Character, at: TurnPoint) -> Vec<String> {
+    let mut messages = vec![];
+
+    for condition in &mut character.conditions {
+        if condition.ends == at {
+            condition.rounds = condition.rounds.map(|rounds| rounds.saturating_sub(1));
+        }
+    }
+
+    let name = &character.name;
+    character.conditions.retain(|condition| {
+        let expired = condition.rounds == Some(0);
+        if expired {
+            messages.push(format!("{} is no longer {}", name, condition.name));
+        } else if at == TurnPoint::End && condition.save_ends {
+            messages.push(format!("{} may save against {}", name, condition.name));
+        }
+        !expired
+    });
+
+    messages
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::build_state;
+
+    #[test]
+    fn parse_conditions() {
+        assert_eq!(parse("prone"), Ok(Condition::new("prone")));
+        assert_eq!(
+            parse("ongoing fire 3 start save from Red Dragon"),
+            Ok(Condition {
+                name: "ongoing fire".to_string(),
+                source: Some("Red Dragon".to_string()),
+                rounds: Some(3),
+                ends: TurnPoint::Start,
+                save_ends: true,
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_conditions() {
+        assert!(parse("").is_err());
+        assert!(parse("2 save").is_err());
+        assert!(parse("dazed 0").is_err());
+        assert!(parse("dazed 1 2").is_err());
+    }
+
+    #[test]
+    fn format() {
+        let format = |s| parse(s).unwrap().to_string();
+        assert_eq!(format("prone from Goblin"), "prone");
+        assert_eq!(format("dazed 2"), "dazed (2)");
+        assert_eq!(format("stunned save"), "stunned (save)");
+        assert_eq!(format("slowed 1 save"), "slowed (1, save)");
+    }
+
+    #[test]
+    fn conditions_run_out() {
+        let mut character = build_state().characters.remove(0);
+        character.conditions = vec![
+            parse("dazed 1").unwrap(),
+            parse("blessed 2 start").unwrap(),
+            parse("stunned save").unwrap(),
+        ];
+
+        assert_eq!(tick(&mut character, TurnPoint::Start), Vec::<String>::new());
+        assert_eq!(
+            tick(&mut character, TurnPoint::End),
+            vec![
+                "Player #1 is no longer dazed",
+                "Player #1 may save against stunned"
+            ]
+        );
+        assert_eq!(
+            tick(&mut character, TurnPoint::Start),
+            vec!["Player #1 is no longer blessed"]
+        );
+        let names: Vec<&str> = character
+            .conditions
+            .iter()
+            .map(|c| c.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["stunned"]);
+    }
+}
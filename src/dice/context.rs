@@ -0,0 +1,71 @@
+use super::error::DiceError;
+use std::collections::BTreeMap;
+
+/// Everything an expression may refer to by name.
+#[derive(Debug, Default, Clone)]
+pub struct Context {
+    /// Named expressions, referred to as `#name`.
+    pub macros: BTreeMap<String, String>,
+    /// Attributes of the selected character, referred to as `@name`.
+    pub variables: BTreeMap<String, i64>,
+}
+
+impl Context {
+    /// The value of the variable `name`, referred to at `pos`.
+    pub fn variable(&self, pos: usize, name: &str) -> Result<i64, DiceError> {
+        self.variables
+            .get(name)
+            .copied()
+            .ok_or_else(|| DiceError::UnknownVariable {
+                pos,
+                name: name.to_string(),
+            })
+    }
+}
+
+/// Whether `name` can be used to refer to a macro or variable, i.e. is
+/// non-empty and made of ASCII letters, digits and underscores.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(is_name_char)
+}
+
+pub fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Splits a definition such as `greatsword = 2d6+4` into its name and
+/// trimmed value. The name may be given with its leading `sigil`.
+pub fn parse_definition(definition: &str, sigil: char) -> Result<(String, String), String> {
+    let i = definition.find('=').ok_or("expected 'name = value'")?;
+    let name = definition[..i].trim();
+    let name = name.strip_prefix(sigil).unwrap_or(name);
+    if !is_valid_name(name) {
+        return Err(format!("invalid name '{}'", name));
+    }
+    Ok((name.to_string(), definition[i + 1..].trim().to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn definition() {
+        assert_eq!(
+            parse_definition("#axe = 1d12", '#'),
+            Ok(("axe".to_string(), "1d12".to_string()))
+        );
+        assert_eq!(
+            parse_definition("str_mod=", '@'),
+            Ok(("str_mod".to_string(), "".to_string()))
+        );
+        assert_eq!(
+            parse_definition("battle axe = 1d12", '#'),
+            Err("invalid name 'battle axe'".to_string())
+        );
+        assert_eq!(
+            parse_definition("axe", '#'),
+            Err("expected 'name = value'".to_string())
+        );
+    }
+}
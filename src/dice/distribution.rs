@@ -0,0 +1,806 @@
+use super::context::Context;
+use super::error::DiceError;
+use super::eval::{
+    apply, divide, operate, rounding, Rounding, MAX_DICE, MAX_EXPLOSIONS, MAX_REROLLS,
+};
+use super::parser::{parse, BinOp, Expr, ExprKind, Function};
+use super::tokenizer::{Compare, CompareOp, Explode, Modifier, Sides};
+use std::collections::BTreeMap;
+
+/// Upper bound on the number of steps spent combining distributions, so that
+/// expressions like `1000d1000` are rejected instead of hanging the UI.
+const MAX_WORK: usize = 10_000_000;
+
+/// Steps spent combining distributions so far, out of a limit.
+struct Work {
+    spent: usize,
+    limit: usize,
+}
+
+/// Outcomes less likely than this are discarded while following long chains
+/// of exploding dice.
+const NEGLIGIBLE: f64 = 1e-15;
+
+/// A probability mass function, mapping outcomes to their probability.
+type Pmf = BTreeMap<i64, f64>;
+
+/// The exact probability distribution of a dice expression.
+#[derive(Debug)]
+pub struct Distribution {
+    pmf: Pmf,
+}
+
+impl Distribution {
+    pub fn min(&self) -> i64 {
+        // a distribution always has at least one outcome
+        *self.pmf.keys().next().unwrap()
+    }
+
+    pub fn max(&self) -> i64 {
+        *self.pmf.keys().next_back().unwrap()
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.pmf.iter().map(|(&v, &p)| v as f64 * p).sum()
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        let mean = self.mean();
+        let variance: f64 = self
+            .pmf
+            .iter()
+            .map(|(&v, &p)| (v as f64 - mean).powi(2) * p)
+            .sum();
+        variance.sqrt()
+    }
+
+    /// Groups the outcomes into at most `max_buckets` ranges of the same
+    /// width, leaving out the ranges that can't be rolled. Without more
+    /// outcomes than that, every outcome gets a bucket of its own.
+    pub fn buckets(&self, max_buckets: usize) -> Vec<Bucket> {
+        let min = self.min();
+        // the range may not fit in an i64, e.g. for `1d20 * -@big`
+        let span = self.max() as i128 - min as i128 + 1;
+        let count = max_buckets.max(1) as i128;
+        let width = (span + count - 1) / count;
+
+        let mut buckets: Vec<Bucket> = vec![];
+        for (&v, &p) in &self.pmf {
+            let low = min as i128 + (v as i128 - min as i128) / width * width;
+            match buckets.last_mut() {
+                Some(bucket) if bucket.low as i128 == low => {
+                    bucket.high = v;
+                    bucket.probability += p;
+                }
+                _ => buckets.push(Bucket {
+                    low: low as i64,
+                    high: v,
+                    probability: p,
+                    at_least: 0.0,
+                }),
+            }
+        }
+
+        let mut at_least = 0.0;
+        for bucket in buckets.iter_mut().rev() {
+            at_least += bucket.probability;
+            bucket.at_least = at_least;
+        }
+        buckets
+    }
+}
+
+/// Outcomes from `low` to `high` grouped together for a histogram.
+#[derive(Debug, PartialEq)]
+pub struct Bucket {
+    pub low: i64,
+    /// The highest outcome of the bucket that can be rolled.
+    pub high: i64,
+    /// The probability of rolling any outcome of the bucket.
+    pub probability: f64,
+    /// The probability of rolling `low` or higher.
+    pub at_least: f64,
+}
+
+/// Calculates the probability distribution of the expression without
+/// rolling any dice. Repetitions and groups are accepted as long as every
+/// roll is the same, and give the distribution of each one of them.
+pub fn distribution(s: &str, context: &Context) -> Result<Distribution, DiceError> {
+    distribution_within(s, context, MAX_WORK)
+}
+
+/// Like [`distribution`], but gives up with [`DiceError::TooComplex`] after
+/// `max_work` steps, for when the answer is needed right away.
+pub fn distribution_within(
+    s: &str,
+    context: &Context,
+    max_work: usize,
+) -> Result<Distribution, DiceError> {
+    let program = parse(s, context)?;
+    let expr = &program.exprs[0];
+    if let Some(other) = program.exprs[1..]
+        .iter()
+        .find(|other| other.to_string() != expr.to_string())
+    {
+        return Err(DiceError::MixedGroup { pos: other.pos });
+    }
+    let mut work = Work {
+        spent: 0,
+        limit: max_work,
+    };
+    let mut pmf = expr_pmf(expr, context, &mut work)?;
+    if let Some(floor) = program.options.floor {
+        pmf = map(&pmf, |v| v.max(floor));
+    }
+    Ok(Distribution { pmf })
+}
+
+fn expr_pmf(expr: &Expr, context: &Context, work: &mut Work) -> Result<Pmf, DiceError> {
+    let pos = expr.pos;
+    match &expr.kind {
+        ExprKind::Num(n) => Ok(point(*n)),
+        ExprKind::Roll {
+            number,
+            sides,
+            modifiers,
+        } => {
+            if *number > MAX_DICE {
+                return Err(DiceError::TooManyDice {
+                    pos,
+                    count: *number,
+                    max: MAX_DICE,
+                });
+            }
+            if sides.count() == 0 {
+                return Err(DiceError::NoSides { pos });
+            }
+            roll_pmf(*number, sides, modifiers, work).ok_or(DiceError::TooComplex { pos })
+        }
+        ExprKind::Neg(operand) => {
+            let operand = expr_pmf(operand, context, work)?;
+            try_combine(&operand, &point(0), work, pos, |a, _| {
+                a.checked_neg().ok_or(DiceError::Overflow { pos })
+            })
+        }
+        ExprKind::BinOp { op, lhs, rhs } => {
+            let lhs = expr_pmf(lhs, context, work)?;
+            let rhs = expr_pmf(rhs, context, work)?;
+            try_combine(&lhs, &rhs, work, pos, |a, b| operate(*op, a, b, pos))
+        }
+        ExprKind::Cond {
+            test,
+            then,
+            otherwise,
+        } => {
+            let test = expr_pmf(test, context, work)?;
+            let holds: f64 = test.iter().filter(|(&v, _)| v != 0).map(|(_, &p)| p).sum();
+            let mut result = Pmf::new();
+            // like rolling, a branch that can't be taken is never evaluated
+            for (branch, p) in &[(then, holds), (otherwise, 1.0 - holds)] {
+                if *p > 0.0 {
+                    for (v, q) in expr_pmf(branch, context, work)? {
+                        *result.entry(v).or_insert(0.0) += p * q;
+                    }
+                }
+            }
+            Ok(result)
+        }
+        ExprKind::Call { function, args } => call_pmf(*function, args, pos, context, work),
+        ExprKind::Var(name) => Ok(point(context.variable(pos, name)?)),
+        ExprKind::Macro { name, expr } => {
+            expr_pmf(expr, context, work).map_err(|error| DiceError::InMacro {
+                pos,
+                name: name.clone(),
+                error: Box::new(error),
+            })
+        }
+    }
+}
+
+fn call_pmf(
+    function: Function,
+    args: &[Expr],
+    pos: usize,
+    context: &Context,
+    work: &mut Work,
+) -> Result<Pmf, DiceError> {
+    if let (Some(rounding), [arg]) = (rounding(function), args) {
+        return division_pmf(arg, rounding, context, work);
+    }
+
+    let mut args = args.iter();
+    let first = match args.next() {
+        Some(arg) => expr_pmf(arg, context, work)?,
+        None => return Ok(point(apply(function, &[], pos)?)),
+    };
+    let mut pmf = try_combine(&first, &point(0), work, pos, |a, _| {
+        apply(function, &[a], pos)
+    })?;
+    for arg in args {
+        let arg = expr_pmf(arg, context, work)?;
+        pmf = try_combine(&pmf, &arg, work, pos, |a, b| apply(function, &[a, b], pos))?;
+    }
+    Ok(pmf)
+}
+
+/// The distribution of an argument of a rounding function, which rounds
+/// the exact quotient when the argument is a division.
+fn division_pmf(
+    expr: &Expr,
+    rounding: Rounding,
+    context: &Context,
+    work: &mut Work,
+) -> Result<Pmf, DiceError> {
+    let pos = expr.pos;
+    match &expr.kind {
+        ExprKind::BinOp {
+            op: BinOp::Div,
+            lhs,
+            rhs,
+        } => {
+            let lhs = expr_pmf(lhs, context, work)?;
+            let rhs = expr_pmf(rhs, context, work)?;
+            try_combine(&lhs, &rhs, work, pos, |a, b| divide(a, b, rounding, pos))
+        }
+        ExprKind::Macro { name, expr } => {
+            division_pmf(expr, rounding, context, work).map_err(|error| DiceError::InMacro {
+                pos,
+                name: name.clone(),
+                error: Box::new(error),
+            })
+        }
+        _ => expr_pmf(expr, context, work),
+    }
+}
+
+/// The distribution of a roll with modifiers, applied in the same order as
+/// when rolling: rerolls, explosions, keep/drop and finally success counting.
+/// Returns `None` if the roll is too complex to calculate.
+fn roll_pmf(number: u32, sides: &Sides, modifiers: &[Modifier], work: &mut Work) -> Option<Pmf> {
+    // every face is visited, so dice with too many sides are rejected first
+    work.spent += sides.count() as usize;
+    if work.spent > work.limit {
+        return None;
+    }
+
+    let mut uniform = Pmf::new();
+    for face in sides.faces() {
+        *uniform.entry(face).or_insert(0.0) += 1.0 / sides.count() as f64;
+    }
+
+    let mut face = uniform.clone();
+    let mut explode = None;
+    let mut keep_drop = None;
+    let mut targets = vec![];
+    let mut failures = vec![];
+
+    for modifier in modifiers {
+        match *modifier {
+            Modifier::Reroll { once, compare } => face = reroll(&face, &uniform, once, compare),
+            Modifier::Explode(kind, compare) => {
+                if explode.is_some() {
+                    return None;
+                }
+                let compare = compare.unwrap_or_else(|| Compare::new(CompareOp::Eq, sides.max()));
+                explode = Some((kind, compare));
+            }
+            Modifier::KeepHighest(..)
+            | Modifier::KeepLowest(..)
+            | Modifier::DropHighest(..)
+            | Modifier::DropLowest(..) => {
+                if keep_drop.is_some() {
+                    return None;
+                }
+                keep_drop = Some(*modifier);
+            }
+            Modifier::Target(compare) => targets.push(compare),
+            Modifier::Failure(compare) => failures.push(compare),
+            // critical hits and fumbles don't change the value
+            Modifier::CritSuccess(..) | Modifier::CritFailure(..) => {}
+        }
+    }
+
+    // dice pools count successes and failures instead of summing values
+    let is_pool = !targets.is_empty() || !failures.is_empty();
+    let score = |v: i64| {
+        if is_pool {
+            let hits = |compares: &[Compare]| compares.iter().any(|c| c.matches(v)) as i64;
+            hits(&targets) - hits(&failures)
+        } else {
+            v
+        }
+    };
+
+    // the value of a single die, or of a whole chain of exploding dice
+    let unit = match explode {
+        None => face,
+        Some((Explode::Compound, compare)) => {
+            chain(&face, &uniform, |v| compare.matches(v), |v| v, |v| v, work)?
+        }
+        Some((kind, compare)) => {
+            if keep_drop.is_some() {
+                // the number of dice to keep or drop from varies
+                return None;
+            }
+            let penalty = if kind == Explode::Penetrate { 1 } else { 0 };
+            let unit = chain(
+                &face,
+                &uniform,
+                |v| compare.matches(v),
+                score,
+                |v| score(v - penalty),
+                work,
+            )?;
+            return repeat(&unit, number, work);
+        }
+    };
+
+    match keep_drop {
+        None => repeat(&map(&unit, score), number, work),
+        Some(modifier) => {
+            let n = number as usize;
+            let (k, highest) = match modifier {
+                Modifier::KeepHighest(k) => (k as usize, true),
+                Modifier::KeepLowest(k) => (k as usize, false),
+                Modifier::DropHighest(k) => (n.saturating_sub(k as usize), false),
+                Modifier::DropLowest(k) => (n.saturating_sub(k as usize), true),
+                _ => unreachable!(),
+            };
+            keep(&unit, n, k.min(n), highest, score, work)
+        }
+    }
+}
+
+fn point(n: i64) -> Pmf {
+    let mut pmf = Pmf::new();
+    pmf.insert(n, 1.0);
+    pmf
+}
+
+fn map(pmf: &Pmf, f: impl Fn(i64) -> i64) -> Pmf {
+    let mut result = Pmf::new();
+    for (&v, &p) in pmf {
+        *result.entry(f(v)).or_insert(0.0) += p;
+    }
+    result
+}
+
+/// The distribution of `f(a, b)` for independent `a` and `b`.
+fn combine(a: &Pmf, b: &Pmf, work: &mut Work, f: impl Fn(i64, i64) -> i64) -> Option<Pmf> {
+    try_combine(a, b, work, 0, |x, y| Ok(f(x, y))).ok()
+}
+
+fn try_combine(
+    a: &Pmf,
+    b: &Pmf,
+    work: &mut Work,
+    pos: usize,
+    f: impl Fn(i64, i64) -> Result<i64, DiceError>,
+) -> Result<Pmf, DiceError> {
+    work.spent += a.len() * b.len();
+    if work.spent > work.limit {
+        return Err(DiceError::TooComplex { pos });
+    }
+
+    let mut result = Pmf::new();
+    for (&x, &px) in a {
+        for (&y, &py) in b {
+            *result.entry(f(x, y)?).or_insert(0.0) += px * py;
+        }
+    }
+    Ok(result)
+}
+
+/// The distribution of the sum of `n` independent values from `pmf`.
+fn repeat(pmf: &Pmf, n: u32, work: &mut Work) -> Option<Pmf> {
+    let mut result = point(0);
+    let mut base = pmf.clone();
+    let mut n = n;
+    while n > 0 {
+        if n & 1 == 1 {
+            result = combine(&result, &base, work, |a, b| a + b)?;
+        }
+        n >>= 1;
+        if n > 0 {
+            base = combine(&base, &base, work, |a, b| a + b)?;
+        }
+    }
+    Some(result)
+}
+
+/// The distribution of a die after rerolling faces matching the comparison,
+/// either once or up to `MAX_REROLLS` times.
+fn reroll(face: &Pmf, uniform: &Pmf, once: bool, compare: Compare) -> Pmf {
+    let max_rerolls = if once { 1 } else { MAX_REROLLS };
+    let rerolled = |pmf: &Pmf| -> f64 {
+        pmf.iter()
+            .filter(|(&v, _)| compare.matches(v))
+            .map(|(_, &p)| p)
+            .sum()
+    };
+
+    // distribution of a fresh roll that may still be rerolled `r` times
+    let mut fresh = uniform.clone();
+    let q = rerolled(uniform);
+    for _ in 1..max_rerolls {
+        let mut next: Pmf = uniform
+            .iter()
+            .filter(|(&v, _)| !compare.matches(v))
+            .map(|(&v, &p)| (v, p))
+            .collect();
+        for (&v, &p) in &fresh {
+            *next.entry(v).or_insert(0.0) += q * p;
+        }
+        fresh = next;
+    }
+
+    let q = rerolled(face);
+    let mut result: Pmf = face
+        .iter()
+        .filter(|(&v, _)| !compare.matches(v))
+        .map(|(&v, &p)| (v, p))
+        .collect();
+    for (&v, &p) in &fresh {
+        *result.entry(v).or_insert(0.0) += q * p;
+    }
+    result
+}
+
+/// The distribution of the summed contributions of a die and all the extra
+/// dice it explodes into, following the explosion limit of the evaluator.
+fn chain(
+    first: &Pmf,
+    extra: &Pmf,
+    explodes: impl Fn(i64) -> bool,
+    first_value: impl Fn(i64) -> i64,
+    extra_value: impl Fn(i64) -> i64,
+    work: &mut Work,
+) -> Option<Pmf> {
+    // `rest` is the distribution of an extra die and everything after it,
+    // built from the last possible explosion backwards
+    let mut rest: Option<Pmf> = None;
+    for _ in 0..MAX_EXPLOSIONS {
+        let mut next = Pmf::new();
+        for (&v, &p) in extra {
+            let value = extra_value(v);
+            match &rest {
+                Some(rest) if explodes(v) => {
+                    work.spent += rest.len();
+                    for (&r, &q) in rest {
+                        if p * q >= NEGLIGIBLE {
+                            *next.entry(value + r).or_insert(0.0) += p * q;
+                        }
+                    }
+                }
+                _ => *next.entry(value).or_insert(0.0) += p,
+            }
+        }
+        rest = Some(next);
+        if work.spent > work.limit {
+            return None;
+        }
+    }
+
+    let rest = rest.unwrap_or_else(|| point(0));
+    let mut result = Pmf::new();
+    for (&v, &p) in first {
+        let value = first_value(v);
+        if explodes(v) {
+            for (&r, &q) in &rest {
+                *result.entry(value + r).or_insert(0.0) += p * q;
+            }
+        } else {
+            *result.entry(value).or_insert(0.0) += p;
+        }
+    }
+    Some(result)
+}
+
+/// The distribution of the summed scores of the `k` highest (or lowest) of
+/// `n` independent dice.
+fn keep(
+    pmf: &Pmf,
+    n: usize,
+    k: usize,
+    highest: bool,
+    score: impl Fn(i64) -> i64,
+    work: &mut Work,
+) -> Option<Pmf> {
+    let mut values: Vec<(i64, f64)> = pmf.iter().map(|(&v, &p)| (v, p)).collect();
+    if highest {
+        values.reverse();
+    }
+
+    // states[j] is the distribution of the kept sum, given that `j` dice have
+    // been assigned one of the values seen so far
+    let mut states: Vec<Pmf> = vec![Pmf::new(); n + 1];
+    states[0] = point(0);
+
+    for (v, p) in values {
+        let mut next: Vec<Pmf> = vec![Pmf::new(); n + 1];
+        for (j, state) in states.iter().enumerate() {
+            // the weight of `c` more dice showing `v`, i.e. `binomial(n - j, c) * p^c`
+            let mut weight = 1.0;
+            for c in 0..=(n - j) {
+                if c > 0 {
+                    weight *= (n - j - c + 1) as f64 / c as f64 * p;
+                }
+                work.spent += 1 + state.len();
+                if weight == 0.0 {
+                    continue;
+                }
+                let kept = c.min(k.saturating_sub(j)) as i64;
+                for (&s, &q) in state {
+                    *next[j + c].entry(s + kept * score(v)).or_insert(0.0) += q * weight;
+                }
+            }
+            if work.spent > work.limit {
+                return None;
+            }
+        }
+        states = next;
+    }
+
+    Some(states.swap_remove(n))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    impl Distribution {
+        /// The probability of the result being exactly `n`.
+        fn probability(&self, n: i64) -> f64 {
+            self.pmf.get(&n).cloned().unwrap_or(0.0)
+        }
+
+        /// The probability of the result being `n` or higher.
+        fn at_least(&self, n: i64) -> f64 {
+            self.pmf.range(n..).map(|(_, &p)| p).sum()
+        }
+    }
+
+    fn distribution(s: &str) -> Result<Distribution, DiceError> {
+        super::distribution(s, &Context::default())
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn crit_ranges_keep_the_odds() {
+        let plain = distribution("1d20").unwrap().pmf;
+        assert_eq!(distribution("1d20cs>=19cf<=2").unwrap().pmf, plain);
+    }
+
+    #[test]
+    fn repeated_rolls() {
+        let single = distribution("4d6kh3").unwrap().pmf;
+        assert_eq!(distribution("6x 4d6kh3").unwrap().pmf, single);
+        assert_eq!(distribution("{4d6kh3, 4d6kh3}").unwrap().pmf, single);
+        assert_eq!(
+            distribution("{1d20, 1d6}").unwrap_err(),
+            DiceError::MixedGroup { pos: 7 }
+        );
+    }
+
+    #[test]
+    fn single_die() {
+        let d = distribution("1d6").unwrap();
+        assert_eq!(d.min(), 1);
+        assert_eq!(d.max(), 6);
+        assert_close(d.mean(), 3.5);
+        assert_close(d.std_dev(), (35.0f64 / 12.0).sqrt());
+    }
+
+    #[test]
+    fn fate_dice() {
+        let d = distribution("4dF").unwrap();
+        assert_eq!(d.min(), -4);
+        assert_eq!(d.max(), 4);
+        assert_close(d.mean(), 0.0);
+        assert_close(d.probability(4), 1.0 / 81.0);
+    }
+
+    #[test]
+    fn custom_faces() {
+        let d = distribution("1d{1,1,2,3,5,8}").unwrap();
+        assert_close(d.probability(1), 1.0 / 3.0);
+        assert_close(d.probability(4), 0.0);
+        assert_close(d.mean(), 20.0 / 6.0);
+    }
+
+    #[test]
+    fn sum_of_dice() {
+        let d = distribution("2d6 + 1").unwrap();
+        assert_eq!(d.min(), 3);
+        assert_eq!(d.max(), 13);
+        assert_close(d.probability(8), 6.0 / 36.0);
+        assert_close(d.at_least(12), 3.0 / 36.0);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let d = distribution("-(1d4 * 2) / 2").unwrap();
+        assert_eq!(d.min(), -4);
+        assert_eq!(d.max(), -1);
+        assert_close(d.mean(), -2.5);
+    }
+
+    #[test]
+    fn conditional() {
+        let d = distribution("1d20 + 5 >= 15 ? 2d6 + 3 : 0").unwrap();
+        assert_close(d.probability(0), 9.0 / 20.0);
+        assert_close(d.probability(15), 11.0 / 20.0 / 36.0);
+        assert_close(d.mean(), 11.0 / 20.0 * 10.0);
+    }
+
+    #[test]
+    fn comparison() {
+        let d = distribution("1d6 >= 5").unwrap();
+        assert_close(d.probability(1), 1.0 / 3.0);
+        assert_close(d.probability(0), 2.0 / 3.0);
+    }
+
+    #[test]
+    fn functions() {
+        let d = distribution("max(1d6, 1d6)").unwrap();
+        assert_close(d.probability(6), 11.0 / 36.0);
+        assert_close(d.probability(1), 1.0 / 36.0);
+
+        let d = distribution("abs(1d4 - 3)").unwrap();
+        assert_eq!(d.min(), 0);
+        assert_close(d.probability(1), 0.5);
+    }
+
+    #[test]
+    fn rounding_functions() {
+        let d = distribution("1d4 / 2").unwrap();
+        assert_close(d.probability(0), 0.25);
+        assert_close(d.probability(2), 0.25);
+
+        let d = distribution("ceil(1d4 / 2)").unwrap();
+        assert_close(d.probability(1), 0.5);
+        assert_close(d.probability(2), 0.5);
+    }
+
+    #[test]
+    fn keep_highest() {
+        let d = distribution("2d20kh1").unwrap();
+        assert_close(d.mean(), 13.825);
+        assert_close(d.at_least(20), 39.0 / 400.0);
+    }
+
+    #[test]
+    fn keep_lowest() {
+        let d = distribution("2d20kl1").unwrap();
+        assert_close(d.mean(), 7.175);
+    }
+
+    #[test]
+    fn drop_lowest() {
+        let d = distribution("4d6dl1").unwrap();
+        assert_close(d.mean(), 15869.0 / 1296.0);
+        assert_eq!(d.min(), 3);
+        assert_eq!(d.max(), 18);
+    }
+
+    #[test]
+    fn explode() {
+        let d = distribution("1d6!").unwrap();
+        assert_close(d.mean(), 4.2);
+        assert_close(d.probability(6), 0.0);
+        assert_close(d.probability(7), 1.0 / 36.0);
+    }
+
+    #[test]
+    fn compound_and_keep() {
+        let d = distribution("2d6!!kh1").unwrap();
+        assert_close(d.probability(1), 1.0 / 36.0);
+        assert_close(d.probability(6), 0.0);
+    }
+
+    #[test]
+    fn penetrate() {
+        let d = distribution("1d6!p").unwrap();
+        assert_close(d.probability(6), 1.0 / 36.0);
+        assert_close(d.mean(), 4.0);
+    }
+
+    #[test]
+    fn reroll_once() {
+        let d = distribution("1d6ro1").unwrap();
+        assert_close(d.probability(1), 1.0 / 36.0);
+        assert_close(d.mean(), 23.5 / 6.0);
+    }
+
+    #[test]
+    fn reroll() {
+        let d = distribution("1d6r1").unwrap();
+        assert!(d.probability(1) < 1e-60);
+        assert_close(d.mean(), 4.0);
+    }
+
+    #[test]
+    fn success_pool() {
+        let d = distribution("3d6>=5").unwrap();
+        assert_close(d.mean(), 1.0);
+        assert_close(d.probability(3), 1.0 / 27.0);
+
+        let d = distribution("2d10>=7f1").unwrap();
+        assert_eq!(d.min(), -2);
+        assert_close(d.probability(-2), 0.01);
+    }
+
+    #[test]
+    fn floor() {
+        let d = distribution("1d4 - 2; floor 1").unwrap();
+        assert_close(d.probability(1), 0.75);
+    }
+
+    #[test]
+    fn division_by_zero() {
+        assert_eq!(
+            distribution("1d6 / (1d2 - 1)").unwrap_err(),
+            DiceError::DivisionByZero { pos: 4 }
+        );
+    }
+
+    #[test]
+    fn too_complex() {
+        assert_eq!(
+            distribution("20d20 * 20d20 * 20d20").unwrap_err(),
+            DiceError::TooComplex { pos: 14 }
+        );
+        assert_eq!(
+            distribution("4d6!kh3").unwrap_err(),
+            DiceError::TooComplex { pos: 0 }
+        );
+    }
+
+    #[test]
+    fn huge_rolls_fail_fast() {
+        for s in &["1d20000000", "1000d1000kh1"] {
+            let start = Instant::now();
+            assert_eq!(
+                distribution(s).unwrap_err(),
+                DiceError::TooComplex { pos: 0 },
+                "{}",
+                s
+            );
+            assert!(start.elapsed() < Duration::from_secs(2), "{}", s);
+        }
+    }
+
+    #[test]
+    fn buckets() {
+        let buckets = distribution("1d4 * 2").unwrap().buckets(100);
+        let lows: Vec<i64> = buckets.iter().map(|b| b.low).collect();
+        assert_eq!(lows, vec![2, 4, 6, 8]);
+        assert_close(buckets[1].probability, 0.25);
+        assert_close(buckets[1].at_least, 0.75);
+
+        let buckets = distribution("1d200000").unwrap().buckets(100);
+        assert_eq!(buckets.len(), 100);
+        assert_eq!((buckets[0].low, buckets[0].high), (1, 2000));
+        assert_close(buckets[0].probability, 0.01);
+        assert_close(buckets[0].at_least, 1.0);
+        assert_eq!(buckets[99].high, 200000);
+
+        let buckets = distribution("1d20 * 1000000").unwrap().buckets(100);
+        assert_eq!(buckets.len(), 20);
+    }
+
+    #[test]
+    fn smaller_budget() {
+        let context = Context::default();
+        assert!(distribution_within("2d6", &context, 1_000).is_ok());
+        assert_eq!(
+            distribution_within("20d20", &context, 1_000).unwrap_err(),
+            DiceError::TooComplex { pos: 0 }
+        );
+        assert!(distribution("20d20").is_ok());
+    }
+}
@@ -0,0 +1,179 @@
+use super::eval::MAX_ROLLS;
+use super::parser::Function;
+use std::fmt;
+
+/// An error from parsing or evaluating a dice expression. Every variant
+/// carries the byte offset into the expression where the error occurred.
+#[derive(Debug, PartialEq, Clone)]
+pub enum DiceError {
+    UnexpectedCharacter {
+        pos: usize,
+        ch: char,
+    },
+    UnexpectedEnd {
+        pos: usize,
+    },
+    MismatchedParen {
+        pos: usize,
+    },
+    MismatchedBrace {
+        pos: usize,
+    },
+    /// A group or repetition inside an expression.
+    NestedGroup {
+        pos: usize,
+    },
+    /// Too many or no rolls requested by repetitions and groups.
+    RollCount {
+        pos: usize,
+        count: usize,
+    },
+    /// Odds requested for a group of different expressions.
+    MixedGroup {
+        pos: usize,
+    },
+    MissingOperand {
+        pos: usize,
+    },
+    MissingOperator {
+        pos: usize,
+    },
+    DivisionByZero {
+        pos: usize,
+    },
+    Overflow {
+        pos: usize,
+    },
+    /// More dice than `max` in a single roll, or in a whole input.
+    TooManyDice {
+        pos: usize,
+        count: u32,
+        max: u32,
+    },
+    NoSides {
+        pos: usize,
+    },
+    TooComplex {
+        pos: usize,
+    },
+    UnknownMacro {
+        pos: usize,
+        name: String,
+    },
+    RecursiveMacro {
+        pos: usize,
+        name: String,
+    },
+    UnknownVariable {
+        pos: usize,
+        name: String,
+    },
+    UnknownFunction {
+        pos: usize,
+        name: String,
+    },
+    /// A function name not followed by its arguments.
+    ExpectedOpenParen {
+        pos: usize,
+    },
+    /// A conditional without the `:` separating its branches.
+    ExpectedColon {
+        pos: usize,
+    },
+    WrongArgumentCount {
+        pos: usize,
+        function: Function,
+        count: usize,
+    },
+    /// A conditional in the damage of a critical hit. Its test would be
+    /// rolled again along with the damage.
+    ConditionalDamage {
+        pos: usize,
+    },
+    /// An error in the expression of a macro, positioned at the reference to
+    /// the macro.
+    InMacro {
+        pos: usize,
+        name: String,
+        error: Box<DiceError>,
+    },
+}
+
+impl DiceError {
+    pub fn pos(&self) -> usize {
+        match self {
+            DiceError::UnexpectedCharacter { pos, .. } => *pos,
+            DiceError::UnexpectedEnd { pos } => *pos,
+            DiceError::MismatchedParen { pos } => *pos,
+            DiceError::MismatchedBrace { pos } => *pos,
+            DiceError::NestedGroup { pos } => *pos,
+            DiceError::RollCount { pos, .. } => *pos,
+            DiceError::MixedGroup { pos } => *pos,
+            DiceError::MissingOperand { pos } => *pos,
+            DiceError::MissingOperator { pos } => *pos,
+            DiceError::DivisionByZero { pos } => *pos,
+            DiceError::Overflow { pos } => *pos,
+            DiceError::TooManyDice { pos, .. } => *pos,
+            DiceError::NoSides { pos } => *pos,
+            DiceError::TooComplex { pos } => *pos,
+            DiceError::UnknownMacro { pos, .. } => *pos,
+            DiceError::RecursiveMacro { pos, .. } => *pos,
+            DiceError::UnknownVariable { pos, .. } => *pos,
+            DiceError::UnknownFunction { pos, .. } => *pos,
+            DiceError::ExpectedOpenParen { pos } => *pos,
+            DiceError::ExpectedColon { pos } => *pos,
+            DiceError::WrongArgumentCount { pos, .. } => *pos,
+            DiceError::ConditionalDamage { pos } => *pos,
+            DiceError::InMacro { pos, .. } => *pos,
+        }
+    }
+}
+
+impl fmt::Display for DiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiceError::UnexpectedCharacter { ch, .. } => write!(f, "unexpected character '{}'", ch),
+            DiceError::UnexpectedEnd { .. } => write!(f, "unexpected end of expression"),
+            DiceError::MismatchedParen { .. } => write!(f, "mismatched parenthesis"),
+            DiceError::MismatchedBrace { .. } => write!(f, "mismatched brace"),
+            DiceError::NestedGroup { .. } => {
+                write!(f, "groups and repetitions can't be part of an expression")
+            }
+            DiceError::RollCount { count, .. } => {
+                write!(f, "can roll 1 to {} times, not {}", MAX_ROLLS, count)
+            }
+            DiceError::MixedGroup { .. } => {
+                write!(f, "odds can only be calculated for one kind of roll")
+            }
+            DiceError::MissingOperand { .. } => write!(f, "missing operand"),
+            DiceError::MissingOperator { .. } => write!(f, "missing operator"),
+            DiceError::DivisionByZero { .. } => write!(f, "division by zero"),
+            DiceError::Overflow { .. } => write!(f, "result is out of range"),
+            DiceError::TooManyDice { count, max, .. } => {
+                write!(f, "too many dice ({}, at most {})", count, max)
+            }
+            DiceError::NoSides { .. } => write!(f, "dice must have at least one side"),
+            DiceError::TooComplex { .. } => write!(f, "too complex to calculate"),
+            DiceError::UnknownMacro { name, .. } => write!(f, "unknown macro #{}", name),
+            DiceError::RecursiveMacro { name, .. } => {
+                write!(f, "macro #{} refers to itself", name)
+            }
+            DiceError::UnknownVariable { name, .. } => {
+                write!(f, "@{} is not defined for the selected character", name)
+            }
+            DiceError::UnknownFunction { name, .. } => write!(f, "unknown function {}", name),
+            DiceError::ExpectedOpenParen { .. } => write!(f, "expected '('"),
+            DiceError::ExpectedColon { .. } => write!(f, "expected ':'"),
+            DiceError::WrongArgumentCount {
+                function, count, ..
+            } => write!(f, "{} takes {}, got {}", function, function.arity(), count),
+            DiceError::ConditionalDamage { .. } => {
+                write!(
+                    f,
+                    "critical damage can't be conditional, the test would be rerolled"
+                )
+            }
+            DiceError::InMacro { name, error, .. } => write!(f, "in macro #{}: {}", name, error),
+        }
+    }
+}
@@ -1,7 +1,35 @@
-use super::tokenizer::{tokenize, Token};
+use super::context::Context;
+use super::error::DiceError;
+use super::parser::{parse, BinOp, Expr, ExprKind, Function};
+use super::result::{Die, DieFlag, Node, RollResult, Rolls};
+use super::tokenizer::{Compare, CompareOp, Explode, Modifier, Sides};
 use rand::Rng;
+use std::convert::TryFrom;
 
-trait DiceRoller {
+/// Upper bound on the number of dice in a single roll.
+pub const MAX_DICE: u32 = 1000;
+
+/// Upper bound on the number of rolls in a single input, counting every
+/// repetition and every expression of a group.
+pub const MAX_ROLLS: usize = 100;
+
+/// Upper bound on how many times a single die may explode, so that rolls
+/// like `1d1!` terminate.
+pub const MAX_EXPLOSIONS: u32 = 100;
+
+/// Upper bound on how many times a single die may be rerolled, so that rolls
+/// like `1d1r1` terminate.
+pub const MAX_REROLLS: u32 = 100;
+
+/// Upper bound on the number of dice rolled for a whole input, counting
+/// every repetition, reroll and explosion, as the limits above multiply.
+pub const MAX_TOTAL_DICE: u32 = 10_000;
+
+/// A source of die rolls. Any random number generator can be used, and a
+/// seeded one makes rolls reproducible.
+pub trait DiceRoller {
+    /// Rolls a single die, returning which of its faces came up as a value in
+    /// `1..=sides`.
     fn roll(&mut self, sides: u32) -> u32;
 }
 
@@ -11,180 +39,473 @@ impl<R: Rng> DiceRoller for R {
     }
 }
 
-#[derive(Debug)]
-struct EvalError {
-    msg: String,
+pub fn eval(s: &str, context: &Context, roller: &mut impl DiceRoller) -> Result<Rolls, DiceError> {
+    let program = parse(s, context)?;
+    let mut dice_left = MAX_TOTAL_DICE;
+    let results = program
+        .rolls()
+        .map(|expr| {
+            let rolled = eval_expr(expr, context, roller, &mut dice_left)?;
+            Ok(RollResult::new(expr.clone(), rolled, program.options.floor))
+        })
+        .collect::<Result<_, DiceError>>()?;
+    Ok(Rolls {
+        notation: program.to_string(),
+        grouped: program.group || program.repeat > 1,
+        results,
+    })
 }
 
-impl EvalError {
-    fn new(msg: impl Into<String>) -> Self {
-        EvalError { msg: msg.into() }
+/// Evaluates the expression, rolling at most `dice_left` dice and counting
+/// them down.
+fn eval_expr(
+    expr: &Expr,
+    context: &Context,
+    roller: &mut impl DiceRoller,
+    dice_left: &mut u32,
+) -> Result<Node, DiceError> {
+    let pos = expr.pos;
+    let node = match &expr.kind {
+        ExprKind::Num(n) => Node::Num(*n),
+        ExprKind::Roll {
+            number,
+            sides,
+            modifiers,
+        } => {
+            if *number > MAX_DICE {
+                return Err(DiceError::TooManyDice {
+                    pos,
+                    count: *number,
+                    max: MAX_DICE,
+                });
+            }
+            if sides.count() == 0 {
+                return Err(DiceError::NoSides { pos });
+            }
+            let dice = roll_dice(*number, sides, modifiers, roller, dice_left).ok_or(
+                DiceError::TooManyDice {
+                    pos,
+                    count: MAX_TOTAL_DICE + 1,
+                    max: MAX_TOTAL_DICE,
+                },
+            )?;
+            let value = dice_value(&dice, modifiers).ok_or(DiceError::Overflow { pos })?;
+            Node::Roll { dice, value }
+        }
+        ExprKind::Neg(operand) => {
+            let operand = eval_expr(operand, context, roller, dice_left)?;
+            let value = operand
+                .value()
+                .checked_neg()
+                .ok_or(DiceError::Overflow { pos })?;
+            Node::Neg {
+                operand: Box::new(operand),
+                value,
+            }
+        }
+        ExprKind::BinOp { op, lhs, rhs } => {
+            let lhs = eval_expr(lhs, context, roller, dice_left)?;
+            let rhs = eval_expr(rhs, context, roller, dice_left)?;
+            let value = operate(*op, lhs.value(), rhs.value(), pos)?;
+            Node::BinOp {
+                op: *op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+                value,
+            }
+        }
+        ExprKind::Cond {
+            test,
+            then,
+            otherwise,
+        } => {
+            let test = eval_expr(test, context, roller, dice_left)?;
+            // only the branch taken is rolled
+            let holds = test.value() != 0;
+            let branch = eval_expr(
+                if holds { then } else { otherwise },
+                context,
+                roller,
+                dice_left,
+            )?;
+            Node::Cond {
+                value: branch.value(),
+                test: Box::new(test),
+                holds,
+                branch: Box::new(branch),
+            }
+        }
+        ExprKind::Var(name) => Node::Num(context.variable(pos, name)?),
+        ExprKind::Call { function, args } => {
+            let args = args
+                .iter()
+                .map(|arg| eval_expr(arg, context, roller, dice_left))
+                .collect::<Result<Vec<_>, _>>()?;
+            let value = match (rounding(*function), args.as_slice()) {
+                // round the exact quotient rather than the rounded down one
+                (
+                    Some(rounding),
+                    [Node::BinOp {
+                        op: BinOp::Div,
+                        lhs,
+                        rhs,
+                        ..
+                    }],
+                ) => divide(lhs.value(), rhs.value(), rounding, pos)?,
+                _ => {
+                    let values: Vec<i64> = args.iter().map(Node::value).collect();
+                    apply(*function, &values, pos)?
+                }
+            };
+            Node::Call {
+                function: *function,
+                args,
+                value,
+            }
+        }
+        // a macro evaluates to its expression, so its breakdown shows the
+        // dice it rolled
+        ExprKind::Macro { name, expr } => {
+            eval_expr(expr, context, roller, dice_left).map_err(|error| DiceError::InMacro {
+                pos,
+                name: name.clone(),
+                error: Box::new(error),
+            })?
+        }
+    };
+    Ok(node)
+}
+
+/// Applies the binary operator at `pos`.
+pub fn operate(op: BinOp, a: i64, b: i64, pos: usize) -> Result<i64, DiceError> {
+    match op {
+        BinOp::Add => a.checked_add(b).ok_or(DiceError::Overflow { pos }),
+        BinOp::Sub => a.checked_sub(b).ok_or(DiceError::Overflow { pos }),
+        BinOp::Mul => a.checked_mul(b).ok_or(DiceError::Overflow { pos }),
+        BinOp::Div => divide(a, b, Rounding::Down, pos),
+        BinOp::Cmp(op) => Ok(Compare::new(op, b).matches(a) as i64),
     }
 }
 
-pub fn eval(s: &str) -> Option<u32> {
-    let mut rng = rand::thread_rng();
-    eval_with_roller(s, &mut rng)
+/// How a quotient is rounded to a whole number.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Rounding {
+    Down,
+    Up,
+    /// To the nearest whole number, halves away from zero.
+    Nearest,
 }
 
-fn eval_with_roller(s: &str, roller: &mut impl DiceRoller) -> Option<u32> {
-    let mut tokens = tokenize(s)?.into_iter();
-    let tokens = shunting_yard(&mut tokens);
-    eval_tokens(tokens, roller).ok()
+/// The rounding a function applies to a division given as its argument.
+pub fn rounding(function: Function) -> Option<Rounding> {
+    match function {
+        Function::Floor => Some(Rounding::Down),
+        Function::Ceil => Some(Rounding::Up),
+        Function::Round => Some(Rounding::Nearest),
+        Function::Min | Function::Max | Function::Abs => None,
+    }
 }
 
-fn roll_dice(number: u32, sides: u32, roller: &mut impl DiceRoller) -> Vec<u32> {
-    (0..number).map(|_| roller.roll(sides)).collect()
+/// Divides `a` by `b` for the division at `pos`, rounding the quotient.
+pub fn divide(a: i64, b: i64, rounding: Rounding, pos: usize) -> Result<i64, DiceError> {
+    if b == 0 {
+        return Err(DiceError::DivisionByZero { pos });
+    }
+
+    let (a, b) = (a as i128, b as i128);
+    let (quotient, remainder) = (a / b, a % b);
+    let quotient = if remainder == 0 {
+        quotient
+    } else {
+        // the exact quotient lies between the truncated one and the next
+        // whole number away from zero
+        let step = if (remainder < 0) == (b < 0) { 1 } else { -1 };
+        let round_away = match rounding {
+            Rounding::Down => step < 0,
+            Rounding::Up => step > 0,
+            Rounding::Nearest => 2 * remainder.abs() >= b.abs(),
+        };
+        if round_away {
+            quotient + step
+        } else {
+            quotient
+        }
+    };
+    i64::try_from(quotient).map_err(|_| DiceError::Overflow { pos })
 }
 
-fn op_precedence(op: &Token) -> u8 {
-    match op {
-        Token::Sym('/') => 3,
-        Token::Sym('*') => 3,
-        Token::Sym('+') => 2,
-        Token::Sym('-') => 2,
-        _ => panic!("unknown op {:?}", op),
-    }
-}
-
-fn shunting_yard(tokens: &mut impl Iterator<Item = Token>) -> Vec<Token> {
-    let mut output_queue: Vec<Token> = vec![];
-    let mut op_stack: Vec<Token> = vec![];
-
-    while let Some(token) = tokens.next() {
-        match token {
-            Token::Num(..) => output_queue.push(token),
-            Token::Roll { .. } => output_queue.push(token),
-            Token::Sym(..) => {
-                while op_stack.len() > 0 {
-                    let top_op = &op_stack[0];
-                    if *top_op == Token::OpenParen {
-                        break;
-                    }
+/// Applies the function called at `pos` to the values of its arguments,
+/// which are assumed to be whole numbers already.
+pub fn apply(function: Function, values: &[i64], pos: usize) -> Result<i64, DiceError> {
+    match function {
+        Function::Min => Ok(values.iter().copied().min().unwrap_or(0)),
+        Function::Max => Ok(values.iter().copied().max().unwrap_or(0)),
+        Function::Abs => values[0].checked_abs().ok_or(DiceError::Overflow { pos }),
+        Function::Floor | Function::Ceil | Function::Round => Ok(values[0]),
+    }
+}
 
-                    let top_precedence = op_precedence(top_op);
-                    let current_precedence = op_precedence(&token);
+/// Rolls the dice and applies their modifiers, or returns `None` if that
+/// takes more than `dice_left` dice.
+fn roll_dice(
+    number: u32,
+    sides: &Sides,
+    modifiers: &[Modifier],
+    roller: &mut impl DiceRoller,
+    dice_left: &mut u32,
+) -> Option<Vec<Die>> {
+    let mut dice: Vec<Die> = (0..number)
+        .map(|_| roll_die(sides, roller, dice_left))
+        .collect::<Option<_>>()?;
 
-                    // all current operators are left associative
-                    if top_precedence < current_precedence {
-                        break;
-                    }
+    for modifier in modifiers {
+        if let Modifier::Reroll { once, compare } = modifier {
+            dice = reroll_dice(dice, sides, *once, *compare, roller, dice_left)?;
+        }
+    }
 
-                    // pop operators from the operator stack onto the output queue.
-                    let top_op = op_stack.remove(0);
-                    output_queue.push(top_op);
-                }
+    for modifier in modifiers {
+        if let Modifier::Explode(explode, compare) = modifier {
+            let compare = compare.unwrap_or_else(|| Compare::new(CompareOp::Eq, sides.max()));
+            dice = explode_dice(dice, sides, *explode, compare, roller, dice_left)?;
+        }
+    }
 
-                op_stack.insert(0, token);
-            }
-            Token::OpenParen => op_stack.insert(0, Token::OpenParen),
-            Token::CloseParen => {
-                while op_stack[0] != Token::OpenParen {
-                    let top_op = op_stack.remove(0);
-                    output_queue.push(top_op);
-                }
+    for modifier in modifiers {
+        apply_keep_drop(&mut dice, *modifier);
+    }
 
-                // if the stack runs out without finding a left parenthesis, then there are mismatched parentheses
-                if op_stack.len() == 0 {
-                    // TODO
-                    panic!("mismatched parentheses");
-                }
+    for modifier in modifiers {
+        match modifier {
+            Modifier::Target(compare) => mark_dice(&mut dice, *compare, DieFlag::Success),
+            Modifier::Failure(compare) => mark_dice(&mut dice, *compare, DieFlag::Failure),
+            _ => {}
+        }
+    }
 
-                // remove the leftover paren
-                let top_op = op_stack.remove(0);
-                assert!(top_op == Token::OpenParen);
-            }
+    let (crit, fumble) = crit_ranges(sides, modifiers);
+    if let Some(compare) = crit {
+        mark_dice(&mut dice, compare, DieFlag::Crit);
+    }
+    if let Some(compare) = fumble {
+        mark_dice(&mut dice, compare, DieFlag::Fumble);
+    }
+
+    Some(dice)
+}
+
+/// The critical hit and fumble ranges of the dice. A d20 crits on a 20 and
+/// fumbles on a 1 unless the modifiers say otherwise; other dice only when
+/// the modifiers give a range.
+fn crit_ranges(sides: &Sides, modifiers: &[Modifier]) -> (Option<Compare>, Option<Compare>) {
+    let d20 = *sides == Sides::Numbered(20);
+    let mut crit = if d20 {
+        Some(Compare::new(CompareOp::Eq, 20))
+    } else {
+        None
+    };
+    let mut fumble = if d20 {
+        Some(Compare::new(CompareOp::Eq, 1))
+    } else {
+        None
+    };
+    for modifier in modifiers {
+        match modifier {
+            Modifier::CritSuccess(compare) => crit = Some(*compare),
+            Modifier::CritFailure(compare) => fumble = Some(*compare),
+            _ => {}
         }
     }
+    (crit, fumble)
+}
+
+fn roll_die(sides: &Sides, roller: &mut impl DiceRoller, dice_left: &mut u32) -> Option<Die> {
+    *dice_left = dice_left.checked_sub(1)?;
+    Some(Die::new(sides.face(roller.roll(sides.count()))))
+}
 
-    // if op stack not empty, pop everything to output queue
-    while op_stack.len() > 0 {
-        let op = op_stack.remove(0);
-        output_queue.push(op);
+fn mark_dice(dice: &mut [Die], compare: Compare, flag: DieFlag) {
+    for die in dice.iter_mut().filter(|d| d.counts()) {
+        if compare.matches(die.value) {
+            die.flags.insert(flag);
+        }
     }
+}
+
+/// The value of a rolled set of dice: either the sum of the dice, or the
+/// number of successes minus the number of failures for dice pools.
+fn dice_value(dice: &[Die], modifiers: &[Modifier]) -> Option<i64> {
+    let is_pool = modifiers
+        .iter()
+        .any(|m| matches!(m, Modifier::Target(..) | Modifier::Failure(..)));
 
-    output_queue
+    if is_pool {
+        let count = |flag| dice.iter().filter(|d| d.flags.contains(flag)).count() as i64;
+        Some(count(DieFlag::Success) - count(DieFlag::Failure))
+    } else {
+        dice.iter()
+            .filter(|d| d.counts())
+            .try_fold(0i64, |sum, d| sum.checked_add(d.value))
+    }
 }
 
-// TODO use something more concrete than tokens directly?
-fn eval_tokens(tokens: Vec<Token>, roller: &mut impl DiceRoller) -> Result<u32, EvalError> {
-    fn apply(stack: &mut Vec<u32>, f: impl Fn(u32, u32) -> u32) {
-        let b = stack.remove(stack.len() - 1);
-        let a = stack.remove(stack.len() - 1);
-        let result = f(a, b);
-        stack.push(result);
+/// Rerolls dice matching the comparison. The original dice are kept, marked
+/// as rerolled, directly before the dice that replaced them.
+fn reroll_dice(
+    dice: Vec<Die>,
+    sides: &Sides,
+    once: bool,
+    compare: Compare,
+    roller: &mut impl DiceRoller,
+    dice_left: &mut u32,
+) -> Option<Vec<Die>> {
+    let max_rerolls = if once { 1 } else { MAX_REROLLS };
+    let mut result = vec![];
+
+    for mut die in dice {
+        let mut rerolls = 0;
+
+        while compare.matches(die.value) && rerolls < max_rerolls {
+            rerolls += 1;
+            die.flags.insert(DieFlag::Rerolled);
+            result.push(die);
+            die = roll_die(sides, roller, dice_left)?;
+        }
+
+        result.push(die);
     }
 
-    let mut stack: Vec<u32> = vec![];
+    Some(result)
+}
+
+fn explode_dice(
+    dice: Vec<Die>,
+    sides: &Sides,
+    explode: Explode,
+    compare: Compare,
+    roller: &mut impl DiceRoller,
+    dice_left: &mut u32,
+) -> Option<Vec<Die>> {
+    let mut result = vec![];
+
+    for mut die in dice {
+        let mut last = die.value;
+        let mut explosions = 0;
+
+        while compare.matches(last) && explosions < MAX_EXPLOSIONS {
+            explosions += 1;
+            die.flags.insert(DieFlag::Exploded);
+            last = roll_die(sides, roller, dice_left)?.value;
 
-    for token in tokens.into_iter() {
-        match token {
-            Token::Num(n) => stack.push(n),
-            Token::Roll { number, sides } => {
-                let result = roll_dice(number, sides, roller).iter().sum();
-                stack.push(result);
+            match explode {
+                Explode::Compound => die.value = die.value.saturating_add(last),
+                Explode::Standard => {
+                    result.push(die);
+                    die = Die::new(last);
+                }
+                Explode::Penetrate => {
+                    result.push(die);
+                    die = Die::new(last - 1);
+                }
             }
-            Token::Sym('+') => apply(&mut stack, |a, b| a + b),
-            Token::Sym('-') => apply(&mut stack, |a, b| a - b),
-            Token::Sym('*') => apply(&mut stack, |a, b| a * b),
-            Token::Sym('/') => apply(&mut stack, |a, b| a / b),
-            _ => return Err(EvalError::new(format!("Unknown token {:?}", token))),
         }
+
+        result.push(die);
     }
 
-    if stack.len() != 1 {
-        Err(EvalError::new("Stack not empty after evaluation finished."))
-    } else {
-        Ok(stack[0])
+    Some(result)
+}
+
+/// Marks dice as dropped according to a keep/drop modifier. Dice that were
+/// already dropped by an earlier modifier are not considered, and other kinds
+/// of modifiers are ignored.
+fn apply_keep_drop(dice: &mut [Die], modifier: Modifier) {
+    let mut indices: Vec<usize> = (0..dice.len()).filter(|&i| dice[i].counts()).collect();
+    // stable sort, so that ties are resolved in roll order
+    indices.sort_by_key(|&i| dice[i].value);
+
+    let n = indices.len();
+    let to_drop = match modifier {
+        Modifier::KeepHighest(k) => &indices[..n.saturating_sub(k as usize)],
+        Modifier::KeepLowest(k) => &indices[(k as usize).min(n)..],
+        Modifier::DropHighest(k) => &indices[n.saturating_sub(k as usize)..],
+        Modifier::DropLowest(k) => &indices[..(k as usize).min(n)],
+        Modifier::Explode(..)
+        | Modifier::Reroll { .. }
+        | Modifier::Target(..)
+        | Modifier::Failure(..)
+        | Modifier::CritSuccess(..)
+        | Modifier::CritFailure(..) => &[],
+    };
+
+    for &i in to_drop {
+        dice[i].flags.insert(DieFlag::Dropped);
     }
 }
 
 #[cfg(test)]
-mod test {
+pub mod test {
     use super::*;
-    use crate::dice::tokenizer::tokenize;
 
-    mod shunting_yard {
+    pub mod eval {
         use super::*;
 
-        #[test]
-        fn simple_add() {
-            let mut tokens = tokenize("1 + 2").unwrap().into_iter();
-            let expected = vec![Token::Num(1), Token::Num(2), Token::Sym('+')];
-            assert_eq!(shunting_yard(&mut tokens), expected);
+        struct MaxDiceRoller;
+
+        /// Rolls the values from the list in order, cycling when exhausted.
+        pub struct FixedDiceRoller {
+            values: Vec<u32>,
+            index: usize,
         }
 
-        #[test]
-        fn mul_precedence() {
-            let mut tokens = tokenize("1 + 2 * 3").unwrap().into_iter();
-            let expected = vec![
-                Token::Num(1),
-                Token::Num(2),
-                Token::Num(3),
-                Token::Sym('*'),
-                Token::Sym('+'),
-            ];
-            assert_eq!(shunting_yard(&mut tokens), expected);
+        impl FixedDiceRoller {
+            pub fn new(values: &[u32]) -> Self {
+                FixedDiceRoller {
+                    values: values.to_vec(),
+                    index: 0,
+                }
+            }
         }
 
-        #[test]
-        fn paren() {
-            let mut tokens = tokenize("(1 + 2) * 3").unwrap().into_iter();
-            let expected = vec![
-                Token::Num(1),
-                Token::Num(2),
-                Token::Sym('+'),
-                Token::Num(3),
-                Token::Sym('*'),
-            ];
-            assert_eq!(shunting_yard(&mut tokens), expected);
+        impl DiceRoller for FixedDiceRoller {
+            fn roll(&mut self, _sides: u32) -> u32 {
+                let value = self.values[self.index % self.values.len()];
+                self.index += 1;
+                value
+            }
         }
-    }
 
-    mod eval {
-        use super::*;
+        fn eval(s: &str, roller: &mut impl DiceRoller) -> Result<RollResult, DiceError> {
+            super::eval(s, &Context::default(), roller).map(|mut rolls| rolls.results.remove(0))
+        }
 
-        struct MaxDiceRoller;
+        fn total(s: &str, roller: &mut impl DiceRoller) -> Result<i64, DiceError> {
+            eval(s, roller).map(|r| r.total)
+        }
+
+        /// Every die rolled for the result, formatted, in roll order.
+        fn dice(result: &RollResult) -> Vec<String> {
+            fn collect(node: &Node, out: &mut Vec<String>) {
+                match node {
+                    Node::Num(_) => {}
+                    Node::Roll { dice, .. } => out.extend(dice.iter().map(|d| d.to_string())),
+                    Node::Neg { operand, .. } => collect(operand, out),
+                    Node::BinOp { lhs, rhs, .. } => {
+                        collect(lhs, out);
+                        collect(rhs, out);
+                    }
+                    Node::Call { args, .. } => args.iter().for_each(|arg| collect(arg, out)),
+                    Node::Cond { test, branch, .. } => {
+                        collect(test, out);
+                        collect(branch, out);
+                    }
+                }
+            }
+
+            let mut out = vec![];
+            collect(&result.rolled, &mut out);
+            out
+        }
 
         impl DiceRoller for MaxDiceRoller {
             fn roll(&mut self, sides: u32) -> u32 {
@@ -194,27 +515,536 @@ mod test {
 
         #[test]
         fn eval_single_die() {
-            assert_eq!(eval_with_roller("1d6", &mut MaxDiceRoller), Some(6));
+            assert_eq!(total("1d6", &mut MaxDiceRoller), Ok(6));
         }
 
         #[test]
         fn eval_multiple_dice() {
-            assert_eq!(eval_with_roller("2d6", &mut MaxDiceRoller), Some(12));
+            assert_eq!(total("2d6", &mut MaxDiceRoller), Ok(12));
         }
 
         #[test]
         fn eval_multiple_different_dice() {
-            assert_eq!(eval_with_roller("1d4 + 1d6", &mut MaxDiceRoller), Some(10));
+            assert_eq!(total("1d4 + 1d6", &mut MaxDiceRoller), Ok(10));
         }
 
         #[test]
         fn addition() {
-            assert_eq!(eval_with_roller("1d6 + 2", &mut MaxDiceRoller), Some(8));
+            assert_eq!(total("1d6 + 2", &mut MaxDiceRoller), Ok(8));
         }
 
         #[test]
         fn subtraction() {
-            assert_eq!(eval_with_roller("1d6 - 2", &mut MaxDiceRoller), Some(4));
+            assert_eq!(total("1d6 - 2", &mut MaxDiceRoller), Ok(4));
+        }
+
+        #[test]
+        fn keep_highest() {
+            let mut roller = FixedDiceRoller::new(&[3, 6, 1, 4]);
+            assert_eq!(total("4d6kh3", &mut roller), Ok(13));
+        }
+
+        #[test]
+        fn keep_lowest() {
+            let mut roller = FixedDiceRoller::new(&[17, 4]);
+            assert_eq!(total("2d20kl1", &mut roller), Ok(4));
+        }
+
+        #[test]
+        fn drop_lowest() {
+            let mut roller = FixedDiceRoller::new(&[3, 6, 1, 4]);
+            assert_eq!(total("4d6dl1", &mut roller), Ok(13));
+        }
+
+        #[test]
+        fn drop_highest() {
+            let mut roller = FixedDiceRoller::new(&[3, 6, 1, 4]);
+            assert_eq!(total("4d6dh2", &mut roller), Ok(4));
+        }
+
+        #[test]
+        fn keep_more_than_rolled() {
+            let mut roller = FixedDiceRoller::new(&[3, 6]);
+            assert_eq!(total("2d6kh5", &mut roller), Ok(9));
+        }
+
+        #[test]
+        fn dropped_dice_are_kept_in_result() {
+            let mut roller = FixedDiceRoller::new(&[3, 6, 1, 4]);
+            let result = eval("4d6kh3", &mut roller).unwrap();
+            assert_eq!(dice(&result), vec!["3", "6", "~1~", "4"]);
+        }
+
+        #[test]
+        fn fate_dice() {
+            let mut roller = FixedDiceRoller::new(&[1, 2, 3, 3]);
+            let result = eval("4dF + 1", &mut roller).unwrap();
+            assert_eq!(result.to_string(), "4dF+1 = [-1, 0, 1, 1] + 1 = 2");
+        }
+
+        #[test]
+        fn percentile_dice() {
+            assert_eq!(total("d%", &mut MaxDiceRoller), Ok(100));
+        }
+
+        #[test]
+        fn custom_faces() {
+            let mut roller = FixedDiceRoller::new(&[1, 6, 4]);
+            let result = eval("3d{1, 1, 2, 3, 5, 8}", &mut roller).unwrap();
+            assert_eq!(result.to_string(), "3d{1,1,2,3,5,8} = [1, 8, 3] = 12");
+        }
+
+        #[test]
+        fn custom_faces_explode_on_highest() {
+            let mut roller = FixedDiceRoller::new(&[2, 1]);
+            assert_eq!(total("d{-1,5,2}!", &mut roller), Ok(4));
+        }
+
+        #[test]
+        fn explode() {
+            let mut roller = FixedDiceRoller::new(&[6, 6, 2]);
+            assert_eq!(total("1d6!", &mut roller), Ok(14));
+        }
+
+        #[test]
+        fn explode_with_compare() {
+            let mut roller = FixedDiceRoller::new(&[9, 10, 3, 4]);
+            let result = eval("2d10!>8", &mut roller).unwrap();
+            assert_eq!(result.total, 26);
+            assert_eq!(dice(&result), vec!["9!", "3", "10!", "4"]);
+        }
+
+        #[test]
+        fn compound() {
+            let mut roller = FixedDiceRoller::new(&[6, 3, 6, 2]);
+            let result = eval("2d6!!", &mut roller).unwrap();
+            assert_eq!(result.total, 17);
+            assert_eq!(dice(&result), vec!["14!", "3"]);
+        }
+
+        #[test]
+        fn penetrate() {
+            let mut roller = FixedDiceRoller::new(&[6, 6, 2]);
+            assert_eq!(total("1d6!p", &mut roller), Ok(12));
+        }
+
+        #[test]
+        fn explode_then_keep() {
+            let mut roller = FixedDiceRoller::new(&[6, 1, 5]);
+            assert_eq!(total("2d6!kh1", &mut roller), Ok(6));
+        }
+
+        #[test]
+        fn explosions_are_capped() {
+            let result = eval("1d1!", &mut MaxDiceRoller).unwrap();
+            assert_eq!(result.total, MAX_EXPLOSIONS as i64 + 1);
+        }
+
+        #[test]
+        fn success_pool() {
+            let mut roller = FixedDiceRoller::new(&[7, 3, 10, 1, 8]);
+            let result = eval("5d10>=7", &mut roller).unwrap();
+            assert_eq!(result.total, 3);
+            assert_eq!(dice(&result), vec!["7*", "3", "10*", "1", "8*"]);
+        }
+
+        #[test]
+        fn success_pool_with_failures() {
+            let mut roller = FixedDiceRoller::new(&[7, 3, 10, 1, 1]);
+            let result = eval("5d10>=7f1", &mut roller).unwrap();
+            assert_eq!(result.total, 0);
+            assert_eq!(dice(&result), vec!["7*", "3", "10*", "1f", "1f"]);
+        }
+
+        #[test]
+        fn success_pool_ignores_dropped_dice() {
+            let mut roller = FixedDiceRoller::new(&[5, 6, 2]);
+            assert_eq!(total("3d6kh2>=5", &mut roller), Ok(2));
+            let mut roller = FixedDiceRoller::new(&[5, 6, 2]);
+            assert_eq!(total("3d6kl2>=5", &mut roller), Ok(1));
+        }
+
+        #[test]
+        fn success_pool_in_expression() {
+            let mut roller = FixedDiceRoller::new(&[6, 2, 5]);
+            assert_eq!(total("3d6>4 + 1", &mut roller), Ok(3));
+        }
+
+        #[test]
+        fn reroll() {
+            let mut roller = FixedDiceRoller::new(&[1, 4, 1, 1, 5]);
+            let result = eval("2d6r1", &mut roller).unwrap();
+            assert_eq!(result.total, 9);
+            assert_eq!(dice(&result), vec!["~1~r", "~1~r", "~1~r", "5", "4"]);
+        }
+
+        #[test]
+        fn reroll_once() {
+            let mut roller = FixedDiceRoller::new(&[2, 5, 1]);
+            let result = eval("2d6ro<3", &mut roller).unwrap();
+            assert_eq!(result.total, 6);
+            assert_eq!(dice(&result), vec!["~2~r", "1", "5"]);
+        }
+
+        #[test]
+        fn reroll_then_keep() {
+            let mut roller = FixedDiceRoller::new(&[1, 3, 6]);
+            assert_eq!(total("2d6r1kl1", &mut roller), Ok(3));
+        }
+
+        #[test]
+        fn rerolls_are_capped() {
+            let result = eval("1d1r1", &mut MaxDiceRoller).unwrap();
+            assert_eq!(dice(&result).len() as u32, MAX_REROLLS + 1);
+            assert_eq!(result.total, 1);
+        }
+
+        #[test]
+        fn format_breakdown() {
+            let mut roller = FixedDiceRoller::new(&[4, 5]);
+            let result = eval("2d6+3", &mut roller).unwrap();
+            assert_eq!(result.to_string(), "2d6+3 = [4, 5] + 3 = 12");
+        }
+
+        #[test]
+        fn format_breakdown_with_modifiers() {
+            let mut roller = FixedDiceRoller::new(&[3, 6, 1, 4]);
+            let result = eval("4d6kh3", &mut roller).unwrap();
+            assert_eq!(result.to_string(), "4d6kh3 = [3, 6, ~1~, 4] = 13");
+        }
+
+        #[test]
+        fn format_breakdown_keeps_parens() {
+            let mut roller = FixedDiceRoller::new(&[2, 3]);
+            let result = eval("(1d4 + 2) * (1d6 - 1)", &mut roller).unwrap();
+            assert_eq!(
+                result.to_string(),
+                "(1d4+2)*(1d6-1) = ([2] + 2) * ([3] - 1) = 8"
+            );
+        }
+
+        #[test]
+        fn format_breakdown_right_associativity() {
+            let result = eval("10 - (2 + 3)", &mut MaxDiceRoller).unwrap();
+            assert_eq!(result.to_string(), "10-(2+3) = 10 - (2 + 3) = 5");
+        }
+
+        #[test]
+        fn format_breakdown_with_macro() {
+            let mut context = Context::default();
+            context
+                .macros
+                .insert("greatsword".to_string(), "2d6+4".to_string());
+            let mut roller = FixedDiceRoller::new(&[4, 5, 3]);
+            let result = super::eval("#greatsword * 2 + 1d6", &context, &mut roller).unwrap();
+            assert_eq!(
+                result.to_string(),
+                "#greatsword*2+1d6 = ([4, 5] + 4) * 2 + [3] = 29"
+            );
+        }
+
+        #[test]
+        fn variables() {
+            let mut context = Context::default();
+            context.variables.insert("str_mod".to_string(), 3);
+            context.variables.insert("prof".to_string(), 2);
+            let mut roller = FixedDiceRoller::new(&[12]);
+            let result = super::eval("1d20 + @str_mod + @prof", &context, &mut roller).unwrap();
+            assert_eq!(
+                result.to_string(),
+                "1d20+@str_mod+@prof = [12] + 3 + 2 = 17"
+            );
+        }
+
+        #[test]
+        fn undefined_variable() {
+            assert_eq!(
+                total("1d20 + @str_mod", &mut MaxDiceRoller),
+                Err(DiceError::UnknownVariable {
+                    pos: 7,
+                    name: "str_mod".to_string()
+                })
+            );
+        }
+
+        #[test]
+        fn error_in_macro() {
+            let mut context = Context::default();
+            context.macros.insert("oops".to_string(), "1/0".to_string());
+            let err = super::eval("1 + #oops", &context, &mut MaxDiceRoller).unwrap_err();
+            assert_eq!(err.pos(), 4);
+            assert_eq!(err.to_string(), "in macro #oops: division by zero");
+        }
+
+        #[test]
+        fn division_by_zero() {
+            assert_eq!(
+                total("1d6 / (2 - 2)", &mut MaxDiceRoller),
+                Err(DiceError::DivisionByZero { pos: 4 })
+            );
+        }
+
+        #[test]
+        fn comparisons() {
+            assert_eq!(total("1d20 >= 20", &mut MaxDiceRoller), Ok(1));
+            assert_eq!(total("1d20 < 20", &mut MaxDiceRoller), Ok(0));
+            assert_eq!(total("1d6 = 6", &mut MaxDiceRoller), Ok(1));
+            assert_eq!(total("1d6 != 6", &mut MaxDiceRoller), Ok(0));
+            assert_eq!(
+                total("(1d6 > 3) + (1d6 > 3) + (1d6 > 3)", &mut MaxDiceRoller),
+                Ok(3)
+            );
+        }
+
+        #[test]
+        fn conditional_hit() {
+            let mut roller = FixedDiceRoller::new(&[14, 3, 4]);
+            let result = eval("1d20+5 >= 15 ? 2d6+3 : 0", &mut roller).unwrap();
+            assert_eq!(
+                result.to_string(),
+                "1d20+5 >= 15 ? 2d6+3 : 0 = [14] + 5 >= 15 ? [3, 4] + 3 : ... = 10"
+            );
+        }
+
+        #[test]
+        fn conditional_miss() {
+            let mut roller = FixedDiceRoller::new(&[3, 6, 6]);
+            let result = eval("1d20+5 >= 15 ? 2d6+3 : 0", &mut roller).unwrap();
+            assert_eq!(
+                result.to_string(),
+                "1d20+5 >= 15 ? 2d6+3 : 0 = [3] + 5 >= 15 ? ... : 0 = 0"
+            );
+            assert_eq!(dice(&result), vec!["3"], "the damage is never rolled");
+        }
+
+        #[test]
+        fn branch_not_taken_is_not_evaluated() {
+            assert_eq!(total("1 ? 2 : 1/0", &mut MaxDiceRoller), Ok(2));
+        }
+
+        #[test]
+        fn critical_hit() {
+            let result = eval("1d20+5", &mut FixedDiceRoller::new(&[20])).unwrap();
+            assert!(result.is_crit());
+            assert!(!result.is_fumble());
+            assert_eq!(result.to_string(), "1d20+5 = [20] + 5 = 25, critical hit");
+        }
+
+        #[test]
+        fn fumble() {
+            let result = eval("1d20+5", &mut FixedDiceRoller::new(&[1])).unwrap();
+            assert!(result.is_fumble());
+            assert_eq!(result.to_string(), "1d20+5 = [1] + 5 = 6, fumble");
+        }
+
+        #[test]
+        fn crit_range() {
+            let result = eval("1d20cs>=19+5", &mut FixedDiceRoller::new(&[19])).unwrap();
+            assert!(result.is_crit());
+            let result = eval("1d20cs>=19cf<=2", &mut FixedDiceRoller::new(&[2])).unwrap();
+            assert!(result.is_fumble());
+            assert_eq!(result.to_string(), "1d20cs>=19cf<=2 = [2] = 2, fumble");
+        }
+
+        #[test]
+        fn dropped_dice_are_not_critical() {
+            let result = eval("2d20kh1", &mut FixedDiceRoller::new(&[20, 1])).unwrap();
+            assert!(result.is_crit());
+            assert!(!result.is_fumble());
+        }
+
+        #[test]
+        fn only_d20s_crit_by_default() {
+            let result = eval("1d6+1d100", &mut FixedDiceRoller::new(&[6, 20])).unwrap();
+            assert!(!result.is_crit());
+            assert!(!result.is_fumble());
+            let result = eval("1d6cs6", &mut FixedDiceRoller::new(&[6])).unwrap();
+            assert!(result.is_crit());
+        }
+
+        #[test]
+        fn repetition() {
+            let mut roller = FixedDiceRoller::new(&[6, 5, 4, 1, 2, 2, 3, 3]);
+            let rolls = super::eval("2x 4d6kh3", &Context::default(), &mut roller).unwrap();
+            assert_eq!(
+                rolls.to_string(),
+                "2x 4d6kh3 = 15, 8\n  \
+                 4d6kh3 = [6, 5, 4, ~1~] = 15\n  \
+                 4d6kh3 = [~2~, 2, 3, 3] = 8"
+            );
+        }
+
+        #[test]
+        fn group() {
+            let mut roller = FixedDiceRoller::new(&[12, 7]);
+            let rolls = super::eval(
+                "{1d20 + 5, 1d20}; floor 10",
+                &Context::default(),
+                &mut roller,
+            )
+            .unwrap();
+            let totals: Vec<i64> = rolls.results.iter().map(|r| r.total).collect();
+            assert_eq!(totals, vec![17, 10]);
+            assert_eq!(
+                rolls.to_string(),
+                "{1d20+5, 1d20} = 17, 10\n  \
+                 1d20+5 = [12] + 5 = 17\n  \
+                 1d20 = [7] = 7, floored to 10"
+            );
+        }
+
+        #[test]
+        fn division_rounds_down() {
+            assert_eq!(total("7 / 2", &mut MaxDiceRoller), Ok(3));
+            assert_eq!(total("-7 / 2", &mut MaxDiceRoller), Ok(-4));
+            assert_eq!(total("7 / -2", &mut MaxDiceRoller), Ok(-4));
+            assert_eq!(total("-8 / 2", &mut MaxDiceRoller), Ok(-4));
+        }
+
+        #[test]
+        fn rounding_functions() {
+            assert_eq!(total("floor(7 / 2)", &mut MaxDiceRoller), Ok(3));
+            assert_eq!(total("ceil(7 / 2)", &mut MaxDiceRoller), Ok(4));
+            assert_eq!(total("ceil(-7 / 2)", &mut MaxDiceRoller), Ok(-3));
+            assert_eq!(total("round(7 / 2)", &mut MaxDiceRoller), Ok(4));
+            assert_eq!(total("round(-7 / 2)", &mut MaxDiceRoller), Ok(-4));
+            assert_eq!(total("round(10 / 3)", &mut MaxDiceRoller), Ok(3));
+            assert_eq!(total("ceil(1d20)", &mut MaxDiceRoller), Ok(20));
+        }
+
+        #[test]
+        fn other_functions() {
+            let mut roller = FixedDiceRoller::new(&[2, 5]);
+            assert_eq!(total("max(1d6, 1d6)", &mut roller), Ok(5));
+            assert_eq!(total("min(1d6, 1d6, 3)", &mut roller), Ok(2));
+            assert_eq!(total("abs(1 - 1d6)", &mut MaxDiceRoller), Ok(5));
+        }
+
+        #[test]
+        fn format_function_breakdown() {
+            let mut roller = FixedDiceRoller::new(&[2, 5, 15]);
+            let result = eval("max(1d6, 1d6) + ceil(1d20 / 2)", &mut roller).unwrap();
+            assert_eq!(
+                result.to_string(),
+                "max(1d6,1d6)+ceil(1d20/2) = max([2], [5]) + ceil([15] / 2) = 13"
+            );
+        }
+
+        #[test]
+        fn rounding_division_by_zero() {
+            assert_eq!(
+                total("ceil(1d6 / 0)", &mut MaxDiceRoller),
+                Err(DiceError::DivisionByZero { pos: 9 })
+            );
+        }
+
+        #[test]
+        fn negative_result() {
+            assert_eq!(total("1d4 - 5", &mut MaxDiceRoller), Ok(-1));
+        }
+
+        #[test]
+        fn unary_minus() {
+            assert_eq!(total("-2 + 1d6", &mut MaxDiceRoller), Ok(4));
+            assert_eq!(total("-(1d6 + 1) * 2", &mut MaxDiceRoller), Ok(-14));
+            assert_eq!(total("3 - -1", &mut MaxDiceRoller), Ok(4));
+        }
+
+        #[test]
+        fn overflow() {
+            assert_eq!(
+                total("4294967295 * 4294967295 * 4294967295", &mut MaxDiceRoller),
+                Err(DiceError::Overflow { pos: 11 })
+            );
+        }
+
+        #[test]
+        fn floor() {
+            let result = eval("1d4 - 5; floor 1", &mut MaxDiceRoller).unwrap();
+            assert_eq!(result.total, 1);
+            assert_eq!(result.to_string(), "1d4-5 = [4] - 5 = -1, floored to 1");
+        }
+
+        #[test]
+        fn floor_not_applied() {
+            let result = eval("1d4 + 5; floor 0", &mut MaxDiceRoller).unwrap();
+            assert_eq!(result.total, 9);
+            assert_eq!(result.to_string(), "1d4+5 = [4] + 5 = 9");
+        }
+
+        #[test]
+        fn format_negation() {
+            let result = eval("-(1d4 + 1) - -2", &mut MaxDiceRoller).unwrap();
+            assert_eq!(result.to_string(), "-(1d4+1)--2 = -([4] + 1) - -2 = -3");
+        }
+
+        #[test]
+        fn botches() {
+            let mut roller = FixedDiceRoller::new(&[1, 3, 1]);
+            assert_eq!(total("3d10>=7f1", &mut roller), Ok(-2));
+        }
+
+        #[test]
+        fn too_many_dice() {
+            assert_eq!(
+                total("2 + 1001d6", &mut MaxDiceRoller),
+                Err(DiceError::TooManyDice {
+                    pos: 4,
+                    count: 1001,
+                    max: MAX_DICE,
+                })
+            );
+        }
+
+        #[test]
+        fn too_many_dice_in_total() {
+            let mut roller = FixedDiceRoller::new(&[2]);
+            assert_eq!(
+                total("100x 1000d2!>=1", &mut roller),
+                Err(DiceError::TooManyDice {
+                    pos: 5,
+                    count: MAX_TOTAL_DICE + 1,
+                    max: MAX_TOTAL_DICE,
+                })
+            );
+            assert_eq!(
+                total("1d2 + 1000d2r<=2", &mut MaxDiceRoller)
+                    .unwrap_err()
+                    .pos(),
+                6
+            );
+        }
+
+        #[test]
+        fn no_sides() {
+            assert_eq!(
+                total("1d0", &mut MaxDiceRoller),
+                Err(DiceError::NoSides { pos: 0 })
+            );
+        }
+
+        #[test]
+        fn missing_operand() {
+            assert_eq!(
+                total("1 +", &mut MaxDiceRoller),
+                Err(DiceError::MissingOperand { pos: 2 })
+            );
+        }
+
+        #[test]
+        fn missing_operator() {
+            assert_eq!(
+                total("1 2", &mut MaxDiceRoller),
+                Err(DiceError::MissingOperator { pos: 2 })
+            );
+        }
+
+        #[test]
+        fn empty() {
+            assert_eq!(
+                total("  ", &mut MaxDiceRoller),
+                Err(DiceError::UnexpectedEnd { pos: 2 })
+            );
         }
     }
 }
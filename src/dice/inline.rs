@@ -0,0 +1,184 @@
+use super::context::Context;
+use super::error::DiceError;
+use super::eval::{eval, DiceRoller};
+use super::parser::parse;
+use super::result::Rolls;
+use std::fmt;
+
+/// Text in which inline rolls such as `[[2d6+1]]` have been rolled, e.g.
+/// `hits for [[2d6+1]] damage`.
+#[derive(Debug, PartialEq)]
+pub struct InlineText {
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Segment {
+    Text(String),
+    /// An inline roll, keeping the expression as it was written.
+    Roll {
+        expr: String,
+        rolls: Rolls,
+    },
+}
+
+impl InlineText {
+    /// The inline rolls, in the order they appear in the text.
+    pub fn rolls(&self) -> impl Iterator<Item = &Rolls> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Roll { rolls, .. } => Some(rolls),
+            Segment::Text(_) => None,
+        })
+    }
+}
+
+/// Formats the text with the totals of its rolls followed by their
+/// expressions, e.g. `hits for 9 (2d6+1) damage`.
+impl fmt::Display for InlineText {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.segments.iter().try_for_each(|segment| match segment {
+            Segment::Text(text) => write!(f, "{}", text),
+            Segment::Roll { expr, rolls } => write!(f, "{} ({})", rolls.totals(), expr),
+        })
+    }
+}
+
+/// An inline roll that could not be parsed or rolled.
+#[derive(Debug, PartialEq)]
+pub struct InlineError {
+    pub expr: String,
+    pub error: DiceError,
+}
+
+impl fmt::Display for InlineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[[{}]]: {}", self.expr, self.error)
+    }
+}
+
+/// Checks every inline roll of the text without rolling it.
+pub fn parse_inline(text: &str, context: &Context) -> Result<(), InlineError> {
+    for (piece, is_roll) in split(text) {
+        if is_roll {
+            parse(piece, context).map_err(|error| inline_error(piece, error))?;
+        }
+    }
+    Ok(())
+}
+
+/// Rolls every inline roll of the text.
+pub fn eval_inline(
+    text: &str,
+    context: &Context,
+    roller: &mut impl DiceRoller,
+) -> Result<InlineText, InlineError> {
+    let mut segments = vec![];
+    for (piece, is_roll) in split(text) {
+        let segment = if is_roll {
+            let rolls = eval(piece, context, roller).map_err(|error| inline_error(piece, error))?;
+            Segment::Roll {
+                expr: piece.trim().to_string(),
+                rolls,
+            }
+        } else {
+            Segment::Text(piece.to_string())
+        };
+        segments.push(segment);
+    }
+    Ok(InlineText { segments })
+}
+
+fn inline_error(expr: &str, error: DiceError) -> InlineError {
+    InlineError {
+        expr: expr.to_string(),
+        error,
+    }
+}
+
+/// Splits the text into plain text and the expressions between `[[` and
+/// `]]`, flagged as `true`. A `[[` without a closing `]]` is plain text.
+fn split(text: &str) -> Vec<(&str, bool)> {
+    let mut pieces = vec![];
+    let mut rest = text;
+    while let Some(open) = rest.find("[[") {
+        let close = match rest[open + 2..].find("]]") {
+            Some(close) => open + 2 + close,
+            None => break,
+        };
+        if open > 0 {
+            pieces.push((&rest[..open], false));
+        }
+        pieces.push((&rest[open + 2..close], true));
+        rest = &rest[close + 2..];
+    }
+    if !rest.is_empty() {
+        pieces.push((rest, false));
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dice::eval::test::eval::FixedDiceRoller;
+
+    fn eval_inline(text: &str, rolls: &[u32]) -> Result<String, InlineError> {
+        let mut roller = FixedDiceRoller::new(rolls);
+        super::eval_inline(text, &Context::default(), &mut roller).map(|text| text.to_string())
+    }
+
+    #[test]
+    fn inline_rolls() {
+        assert_eq!(
+            eval_inline("hits for [[2d6 + 1]] damage", &[4, 4]),
+            Ok("hits for 9 (2d6 + 1) damage".to_string())
+        );
+        assert_eq!(
+            eval_inline("[[1d20]] and [[{1d4, 1d4}]]", &[12, 1, 3]),
+            Ok("12 (1d20) and 1, 3 ({1d4, 1d4})".to_string())
+        );
+    }
+
+    #[test]
+    fn keeps_expressions() {
+        let mut roller = FixedDiceRoller::new(&[5]);
+        let text = super::eval_inline("heals [[1d8+2]]", &Context::default(), &mut roller).unwrap();
+        match &text.segments[1] {
+            Segment::Roll { expr, rolls } => {
+                assert_eq!(expr, "1d8+2");
+                assert_eq!(rolls.results[0].total, 7);
+            }
+            segment => panic!("expected a roll, got {:?}", segment),
+        }
+        let totals: Vec<i64> = text.rolls().map(|rolls| rolls.results[0].total).collect();
+        assert_eq!(totals, vec![7]);
+    }
+
+    #[test]
+    fn plain_text() {
+        assert_eq!(eval_inline("", &[]), Ok("".to_string()));
+        assert_eq!(
+            eval_inline("no [rolls] [[here", &[]),
+            Ok("no [rolls] [[here".to_string())
+        );
+    }
+
+    #[test]
+    fn invalid_inline_roll() {
+        let error = InlineError {
+            expr: "2d6+".to_string(),
+            error: DiceError::MissingOperand { pos: 3 },
+        };
+        assert_eq!(
+            parse_inline("a [[1d4]] b [[2d6+]]", &Context::default()),
+            Err(error)
+        );
+        assert_eq!(
+            eval_inline("[[1d4]] [[]]", &[1]),
+            Err(InlineError {
+                expr: "".to_string(),
+                error: DiceError::UnexpectedEnd { pos: 0 }
+            })
+        );
+    }
+}
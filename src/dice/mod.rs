@@ -1,5 +1,17 @@
+mod context;
+mod distribution;
+mod error;
 mod eval;
+mod inline;
+mod parser;
+mod result;
 mod tokenizer;
 pub mod ui;
 
+pub use context::{parse_definition, Context};
+pub use distribution::{distribution, distribution_within, Distribution};
+pub use error::DiceError;
 pub use eval::eval;
+pub use inline::{eval_inline, parse_inline};
+pub use parser::{double_dice, parse, parse_macro};
+pub use result::Rolls;
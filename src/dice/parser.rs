@@ -0,0 +1,1041 @@
+use super::context::Context;
+use super::error::DiceError;
+use super::eval::MAX_ROLLS;
+use super::tokenizer::{tokenize, tokenize_options, CompareOp, Modifier, Options, Sides, Token};
+use std::fmt;
+use std::iter::Peekable;
+use std::vec::IntoIter;
+
+/// A parsed dice expression. Every node keeps the byte offset of the token
+/// it was parsed from (the operator, for binary operations), so that
+/// evaluation errors can point at the offending part of the input.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expr {
+    pub pos: usize,
+    pub kind: ExprKind,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExprKind {
+    Num(i64),
+    Roll {
+        number: u32,
+        sides: Sides,
+        modifiers: Vec<Modifier>,
+    },
+    Neg(Box<Expr>),
+    BinOp {
+        op: BinOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    /// An attribute of the selected character.
+    Var(String),
+    Call {
+        function: Function,
+        args: Vec<Expr>,
+    },
+    /// `test ? then : otherwise`, where any test other than 0 is true.
+    Cond {
+        test: Box<Expr>,
+        then: Box<Expr>,
+        otherwise: Box<Expr>,
+    },
+    /// A reference to a named expression, parsed in place. Positions inside
+    /// the macro are offsets into its own expression.
+    Macro {
+        name: String,
+        expr: Box<Expr>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    /// Division rounding down, e.g. `7/2` is 3 and `-7/2` is -4.
+    Div,
+    /// A comparison, which is 1 if it holds and 0 otherwise.
+    Cmp(CompareOp),
+}
+
+impl BinOp {
+    fn from_token(token: &Token) -> Option<BinOp> {
+        match token {
+            Token::Sym('+') => Some(BinOp::Add),
+            Token::Sym('-') => Some(BinOp::Sub),
+            Token::Sym('*') => Some(BinOp::Mul),
+            Token::Sym('/') => Some(BinOp::Div),
+            Token::Cmp(op) => Some(BinOp::Cmp(*op)),
+            _ => None,
+        }
+    }
+
+    /// Binding power of the operator. All binary operators are left
+    /// associative.
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinOp::Cmp(_) => 2,
+            BinOp::Add | BinOp::Sub => 3,
+            BinOp::Mul | BinOp::Div => 4,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Function {
+    Min,
+    Max,
+    /// Rounds a division down, which is what `/` already does.
+    Floor,
+    /// Rounds a division up, e.g. `ceil(7/2)` is 4.
+    Ceil,
+    /// Rounds a division to the nearest whole number, halves away from zero.
+    Round,
+    Abs,
+}
+
+/// How many arguments a function takes.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Function> {
+        match name {
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            "floor" => Some(Function::Floor),
+            "ceil" => Some(Function::Ceil),
+            "round" => Some(Function::Round),
+            "abs" => Some(Function::Abs),
+            _ => None,
+        }
+    }
+
+    pub fn arity(self) -> Arity {
+        match self {
+            Function::Min | Function::Max => Arity::AtLeast(1),
+            Function::Floor | Function::Ceil | Function::Round | Function::Abs => Arity::Exactly(1),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Function::Min => "min",
+            Function::Max => "max",
+            Function::Floor => "floor",
+            Function::Ceil => "ceil",
+            Function::Round => "round",
+            Function::Abs => "abs",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (qualifier, n) = match self {
+            Arity::Exactly(n) => ("", n),
+            Arity::AtLeast(n) => ("at least ", n),
+        };
+        let plural = if *n == 1 { "" } else { "s" };
+        write!(f, "{}{} argument{}", qualifier, n, plural)
+    }
+}
+
+/// Binding power of the conditional operator, which binds looser than any
+/// binary operator and is right associative.
+pub const COND_PRECEDENCE: u8 = 1;
+
+/// Binding power of prefix operators, which bind tighter than any binary
+/// operator.
+pub const PREFIX_PRECEDENCE: u8 = 5;
+
+/// Binding power of expressions that never need parentheses.
+pub const ATOM_PRECEDENCE: u8 = 6;
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BinOp::Add => write!(f, "+"),
+            BinOp::Sub => write!(f, "-"),
+            BinOp::Mul => write!(f, "*"),
+            BinOp::Div => write!(f, "/"),
+            BinOp::Cmp(CompareOp::Eq) => write!(f, "=="),
+            BinOp::Cmp(op) => write!(f, "{}", op),
+        }
+    }
+}
+
+impl Expr {
+    fn new(pos: usize, kind: ExprKind) -> Self {
+        Expr { pos, kind }
+    }
+
+    pub fn precedence(&self) -> u8 {
+        match &self.kind {
+            ExprKind::Num(_)
+            | ExprKind::Roll { .. }
+            | ExprKind::Var(_)
+            | ExprKind::Call { .. }
+            | ExprKind::Macro { .. } => ATOM_PRECEDENCE,
+            ExprKind::Neg(_) => PREFIX_PRECEDENCE,
+            ExprKind::BinOp { op, .. } => op.precedence(),
+            ExprKind::Cond { .. } => COND_PRECEDENCE,
+        }
+    }
+
+    /// Whether any roll of the expression, including the rolls of its
+    /// macros, has a modifier for which `f` holds.
+    pub fn has_modifier(&self, f: &dyn Fn(&Modifier) -> bool) -> bool {
+        match &self.kind {
+            ExprKind::Roll { modifiers, .. } => modifiers.iter().any(f),
+            ExprKind::Neg(operand) => operand.has_modifier(f),
+            ExprKind::BinOp { lhs, rhs, .. } => lhs.has_modifier(f) || rhs.has_modifier(f),
+            ExprKind::Call { args, .. } => args.iter().any(|a| a.has_modifier(f)),
+            ExprKind::Cond {
+                test,
+                then,
+                otherwise,
+            } => test.has_modifier(f) || then.has_modifier(f) || otherwise.has_modifier(f),
+            ExprKind::Macro { expr, .. } => expr.has_modifier(f),
+            ExprKind::Num(_) | ExprKind::Var(_) => false,
+        }
+    }
+
+    /// The expression with twice as many dice in every roll, e.g. `4d6+3`
+    /// for `2d6+3`. Macros are replaced by their expressions, so that their
+    /// dice are doubled too. Conditionals are rejected, as the doubled
+    /// expression is rolled from scratch and their test would be rerolled.
+    fn with_doubled_dice(self) -> Result<Expr, DiceError> {
+        let pos = self.pos;
+        let double = |expr: Box<Expr>| expr.with_doubled_dice().map(Box::new);
+        let kind = match self.kind {
+            ExprKind::Roll {
+                number,
+                sides,
+                modifiers,
+            } => ExprKind::Roll {
+                number: number.saturating_mul(2),
+                sides,
+                modifiers,
+            },
+            ExprKind::Neg(operand) => ExprKind::Neg(double(operand)?),
+            ExprKind::BinOp { op, lhs, rhs } => ExprKind::BinOp {
+                op,
+                lhs: double(lhs)?,
+                rhs: double(rhs)?,
+            },
+            ExprKind::Call { function, args } => ExprKind::Call {
+                function,
+                args: args
+                    .into_iter()
+                    .map(Expr::with_doubled_dice)
+                    .collect::<Result<_, _>>()?,
+            },
+            ExprKind::Cond { .. } => return Err(DiceError::ConditionalDamage { pos }),
+            ExprKind::Macro { name, expr } => {
+                return expr
+                    .with_doubled_dice()
+                    .map_err(|error| DiceError::InMacro {
+                        pos,
+                        name,
+                        error: Box::new(error),
+                    })
+            }
+            kind @ ExprKind::Num(_) | kind @ ExprKind::Var(_) => kind,
+        };
+        Ok(Expr::new(pos, kind))
+    }
+}
+
+/// Formats the expression in dice notation, e.g. `(1d4+2)*2`. Comparisons
+/// are spaced out, so that they are not read as modifiers of a roll.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ExprKind::Num(n) => write!(f, "{}", n),
+            ExprKind::Roll {
+                number,
+                sides,
+                modifiers,
+            } => {
+                write!(f, "{}d{}", number, sides)?;
+                modifiers.iter().try_for_each(|m| write!(f, "{}", m))
+            }
+            ExprKind::Neg(operand) => {
+                write!(f, "-")?;
+                write_operand(f, operand, operand.precedence() < PREFIX_PRECEDENCE)
+            }
+            ExprKind::BinOp { op, lhs, rhs } => {
+                write_operand(f, lhs, lhs.precedence() < op.precedence())?;
+                match op {
+                    BinOp::Cmp(_) => write!(f, " {} ", op)?,
+                    _ => write!(f, "{}", op)?,
+                }
+                write_operand(f, rhs, rhs.precedence() <= op.precedence())
+            }
+            ExprKind::Cond {
+                test,
+                then,
+                otherwise,
+            } => {
+                write_operand(f, test, test.precedence() <= COND_PRECEDENCE)?;
+                write!(f, " ? {} : {}", then, otherwise)
+            }
+            ExprKind::Var(name) => write!(f, "@{}", name),
+            ExprKind::Call { function, args } => {
+                let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+                write!(f, "{}({})", function, args.join(","))
+            }
+            ExprKind::Macro { name, .. } => write!(f, "#{}", name),
+        }
+    }
+}
+
+fn write_operand(f: &mut fmt::Formatter, expr: &Expr, parens: bool) -> fmt::Result {
+    if parens {
+        write!(f, "({})", expr)
+    } else {
+        write!(f, "{}", expr)
+    }
+}
+
+/// A complete line of input: the expressions to roll and the options that
+/// apply to each of them.
+#[derive(Debug, PartialEq)]
+pub struct Program {
+    /// The expressions of a group such as `{1d20, 1d20}`, or a single one.
+    pub exprs: Vec<Expr>,
+    pub group: bool,
+    /// How many times the expressions are rolled, e.g. 6 for `6x 4d6kh3`.
+    pub repeat: u32,
+    pub options: Options,
+}
+
+impl Program {
+    /// Every expression to roll, in order.
+    pub fn rolls(&self) -> impl Iterator<Item = &Expr> {
+        (0..self.repeat).flat_map(move |_| self.exprs.iter())
+    }
+}
+
+/// Formats the program in dice notation, e.g. `6x 4d6kh3` or
+/// `{1d20+5, 1d20+5}`.
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.repeat != 1 {
+            write!(f, "{}x ", self.repeat)?;
+        }
+        let exprs: Vec<String> = self.exprs.iter().map(|e| e.to_string()).collect();
+        if self.group {
+            write!(f, "{{{}}}", exprs.join(", "))
+        } else {
+            write!(f, "{}", exprs.join(", "))
+        }
+    }
+}
+
+pub fn parse(s: &str, context: &Context) -> Result<Program, DiceError> {
+    let (body, options) = match s.find(';') {
+        Some(i) => (&s[..i], tokenize_options(s, i)?),
+        None => (s, Options::default()),
+    };
+
+    let mut parser = Parser::new(body, context, &[])?;
+    let repeat = match parser.tokens.peek() {
+        Some(&(_, Token::Repeat(n))) => {
+            parser.next();
+            n
+        }
+        _ => 1,
+    };
+    let (exprs, group) = match parser.tokens.peek() {
+        Some(&(open, Token::OpenBrace)) => {
+            parser.next();
+            (parser.parse_group(open)?, true)
+        }
+        _ => (vec![parser.parse_expr(0)?], false),
+    };
+    parser.finish()?;
+
+    let count = repeat as usize * exprs.len();
+    if count == 0 || count > MAX_ROLLS {
+        return Err(DiceError::RollCount { pos: 0, count });
+    }
+
+    Ok(Program {
+        exprs,
+        group,
+        repeat,
+        options,
+    })
+}
+
+/// Rewrites the input with twice as many dice in every roll, for the damage
+/// of a critical hit: `2d6+3; floor 1` becomes `4d6+3; floor 1`. Damage with
+/// a conditional is rejected, since rolling it would roll the test again.
+pub fn double_dice(s: &str, context: &Context) -> Result<String, DiceError> {
+    let program = parse(s, context)?;
+    let doubled = Program {
+        exprs: program
+            .exprs
+            .into_iter()
+            .map(Expr::with_doubled_dice)
+            .collect::<Result<_, _>>()?,
+        ..program
+    };
+    let options = s.find(';').map_or("", |i| &s[i..]);
+    Ok(format!("{}{}", doubled, options))
+}
+
+/// Checks the expression of a macro the way it is parsed where the macro is
+/// used: without options, repetitions or groups, and without referring to
+/// itself.
+pub fn parse_macro(name: &str, s: &str, context: &Context) -> Result<(), DiceError> {
+    let mut context = context.clone();
+    context.macros.insert(name.to_string(), s.to_string());
+    parse_expression(s, &context, &[name]).map(|_| ())
+}
+
+/// Parses an expression without options. `expanding` holds the macros whose
+/// expressions are being parsed, innermost last.
+fn parse_expression(s: &str, context: &Context, expanding: &[&str]) -> Result<Expr, DiceError> {
+    let mut parser = Parser::new(s, context, expanding)?;
+    let expr = parser.parse_expr(0)?;
+    parser.finish()?;
+    Ok(expr)
+}
+
+/// A Pratt parser over the tokens of an expression.
+struct Parser<'a> {
+    tokens: Peekable<IntoIter<(usize, Token)>>,
+    /// The last consumed token, used to explain where input ran out.
+    prev: Option<(usize, Token)>,
+    end: usize,
+    context: &'a Context,
+    expanding: &'a [&'a str],
+}
+
+impl<'a> Parser<'a> {
+    fn new(s: &str, context: &'a Context, expanding: &'a [&'a str]) -> Result<Self, DiceError> {
+        Ok(Parser {
+            tokens: tokenize(s)?.into_iter().peekable(),
+            prev: None,
+            end: s.len(),
+            context,
+            expanding,
+        })
+    }
+
+    /// Checks that every token was parsed.
+    fn finish(&mut self) -> Result<(), DiceError> {
+        match self.tokens.next() {
+            None => Ok(()),
+            Some((pos, Token::CloseParen)) => Err(DiceError::MismatchedParen { pos }),
+            Some((pos, Token::CloseBrace)) => Err(DiceError::MismatchedBrace { pos }),
+            Some((pos, _)) => Err(DiceError::MissingOperator { pos }),
+        }
+    }
+
+    /// Parses the expressions of a group, following its opening brace at
+    /// `open`.
+    fn parse_group(&mut self, open: usize) -> Result<Vec<Expr>, DiceError> {
+        let mut exprs = vec![];
+        loop {
+            exprs.push(self.parse_expr(0)?);
+            match self.next() {
+                Some((_, Token::Comma)) => continue,
+                Some((_, Token::CloseBrace)) => return Ok(exprs),
+                Some((pos, _)) => return Err(DiceError::MissingOperator { pos }),
+                None => return Err(DiceError::MismatchedBrace { pos: open }),
+            }
+        }
+    }
+
+    fn next(&mut self) -> Option<(usize, Token)> {
+        let next = self.tokens.next();
+        if next.is_some() {
+            self.prev = next.clone();
+        }
+        next
+    }
+
+    fn parse_expr(&mut self, min_precedence: u8) -> Result<Expr, DiceError> {
+        let mut lhs = self.parse_prefix()?;
+
+        while let Some((pos, token)) = self.tokens.peek() {
+            let pos = *pos;
+            if *token == Token::Question {
+                if COND_PRECEDENCE <= min_precedence {
+                    break;
+                }
+                self.next();
+                lhs = self.parse_cond(pos, lhs)?;
+                continue;
+            }
+
+            let op = match BinOp::from_token(token) {
+                Some(op) => op,
+                None => break,
+            };
+
+            if op.precedence() <= min_precedence {
+                break;
+            }
+
+            self.next();
+            let rhs = self.parse_expr(op.precedence())?;
+            lhs = Expr::new(
+                pos,
+                ExprKind::BinOp {
+                    op,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+            );
+        }
+
+        Ok(lhs)
+    }
+
+    /// Parses the branches of a conditional following the `?` at `pos`.
+    fn parse_cond(&mut self, pos: usize, test: Expr) -> Result<Expr, DiceError> {
+        let then = self.parse_expr(0)?;
+        match self.next() {
+            Some((_, Token::Colon)) => {}
+            Some((pos, _)) => return Err(DiceError::ExpectedColon { pos }),
+            None => return Err(DiceError::ExpectedColon { pos: self.end }),
+        }
+        let otherwise = self.parse_expr(COND_PRECEDENCE - 1)?;
+        Ok(Expr::new(
+            pos,
+            ExprKind::Cond {
+                test: Box::new(test),
+                then: Box::new(then),
+                otherwise: Box::new(otherwise),
+            },
+        ))
+    }
+
+    fn parse_prefix(&mut self) -> Result<Expr, DiceError> {
+        let (pos, token) = match self.next() {
+            Some(next) => next,
+            None => return Err(self.unexpected_end()),
+        };
+
+        match token {
+            Token::Num(n) => Ok(Expr::new(pos, ExprKind::Num(n as i64))),
+            Token::Roll {
+                number,
+                sides,
+                modifiers,
+            } => Ok(Expr::new(
+                pos,
+                ExprKind::Roll {
+                    number,
+                    sides,
+                    modifiers,
+                },
+            )),
+            Token::Neg => {
+                let operand = self.parse_expr(PREFIX_PRECEDENCE)?;
+                Ok(Expr::new(pos, ExprKind::Neg(Box::new(operand))))
+            }
+            Token::OpenParen => {
+                let expr = self.parse_expr(0)?;
+                match self.next() {
+                    Some((_, Token::CloseParen)) => Ok(expr),
+                    Some((pos, _)) => Err(DiceError::MissingOperator { pos }),
+                    None => Err(DiceError::MismatchedParen { pos }),
+                }
+            }
+            Token::Macro(name) => self.parse_macro(pos, name),
+            Token::Var(name) => {
+                self.context.variable(pos, &name)?;
+                Ok(Expr::new(pos, ExprKind::Var(name)))
+            }
+            Token::Ident(name) => self.parse_call(pos, name),
+            Token::Repeat(_) | Token::OpenBrace => Err(DiceError::NestedGroup { pos }),
+            Token::CloseParen
+            | Token::CloseBrace
+            | Token::Comma
+            | Token::Sym(_)
+            | Token::Cmp(_)
+            | Token::Question
+            | Token::Colon => Err(DiceError::MissingOperand { pos }),
+        }
+    }
+
+    /// Parses the arguments of a call to the function `name`, from its
+    /// opening parenthesis on.
+    fn parse_call(&mut self, pos: usize, name: String) -> Result<Expr, DiceError> {
+        let function = match Function::from_name(&name) {
+            Some(function) => function,
+            None => return Err(DiceError::UnknownFunction { pos, name }),
+        };
+        let open = match self.next() {
+            Some((open, Token::OpenParen)) => open,
+            Some((pos, _)) => return Err(DiceError::ExpectedOpenParen { pos }),
+            None => return Err(DiceError::ExpectedOpenParen { pos: self.end }),
+        };
+
+        let mut args = vec![];
+        if let Some((_, Token::CloseParen)) = self.tokens.peek() {
+            self.next();
+        } else {
+            loop {
+                args.push(self.parse_expr(0)?);
+                match self.next() {
+                    Some((_, Token::Comma)) => continue,
+                    Some((_, Token::CloseParen)) => break,
+                    Some((pos, _)) => return Err(DiceError::MissingOperator { pos }),
+                    None => return Err(DiceError::MismatchedParen { pos: open }),
+                }
+            }
+        }
+
+        let count = args.len();
+        let accepted = match function.arity() {
+            Arity::Exactly(n) => count == n,
+            Arity::AtLeast(n) => count >= n,
+        };
+        if !accepted {
+            return Err(DiceError::WrongArgumentCount {
+                pos,
+                function,
+                count,
+            });
+        }
+
+        Ok(Expr::new(pos, ExprKind::Call { function, args }))
+    }
+
+    fn parse_macro(&self, pos: usize, name: String) -> Result<Expr, DiceError> {
+        let body = match self.context.macros.get(&name) {
+            Some(body) => body,
+            None => return Err(DiceError::UnknownMacro { pos, name }),
+        };
+        if self.expanding.contains(&name.as_str()) {
+            return Err(DiceError::RecursiveMacro { pos, name });
+        }
+
+        let mut expanding = self.expanding.to_vec();
+        expanding.push(&name);
+        match parse_expression(body, self.context, &expanding) {
+            Ok(expr) => Ok(Expr::new(
+                pos,
+                ExprKind::Macro {
+                    name,
+                    expr: Box::new(expr),
+                },
+            )),
+            Err(error) => Err(DiceError::InMacro {
+                pos,
+                name,
+                error: Box::new(error),
+            }),
+        }
+    }
+
+    /// The error for input ending where an operand was expected.
+    fn unexpected_end(&self) -> DiceError {
+        match &self.prev {
+            Some((pos, Token::OpenParen)) => DiceError::MismatchedParen { pos: *pos },
+            Some((pos, Token::OpenBrace)) => DiceError::MismatchedBrace { pos: *pos },
+            Some((pos, _)) => DiceError::MissingOperand { pos: *pos },
+            None => DiceError::UnexpectedEnd { pos: self.end },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(s: &str) -> Result<Program, DiceError> {
+        super::parse(s, &Context::default())
+    }
+
+    fn parse_expr(s: &str) -> Result<String, DiceError> {
+        parse(s).map(|program| program.to_string())
+    }
+
+    #[test]
+    fn simple_add() {
+        let program = parse("1 + 2").unwrap();
+        let expected = Expr::new(
+            2,
+            ExprKind::BinOp {
+                op: BinOp::Add,
+                lhs: Box::new(Expr::new(0, ExprKind::Num(1))),
+                rhs: Box::new(Expr::new(4, ExprKind::Num(2))),
+            },
+        );
+        assert_eq!(program.exprs[0], expected);
+    }
+
+    #[test]
+    fn mul_precedence() {
+        assert_eq!(parse_expr("1 + 2 * 3"), Ok("1+2*3".to_string()));
+        assert_eq!(parse_expr("(1 + 2) * 3"), Ok("(1+2)*3".to_string()));
+    }
+
+    #[test]
+    fn left_associative() {
+        assert_eq!(parse_expr("10 - 2 - 3"), Ok("10-2-3".to_string()));
+        assert_eq!(parse_expr("10 - (2 - 3)"), Ok("10-(2-3)".to_string()));
+    }
+
+    #[test]
+    fn redundant_parens() {
+        assert_eq!(parse_expr("((1d6)) + (2 * 3)"), Ok("1d6+2*3".to_string()));
+    }
+
+    #[test]
+    fn negation() {
+        assert_eq!(parse_expr("-2 * -(1d6 + 1)"), Ok("-2*-(1d6+1)".to_string()));
+    }
+
+    #[test]
+    fn options() {
+        let program = parse("1d4 - 5; floor 1").unwrap();
+        assert_eq!(program.exprs[0].to_string(), "1d4-5");
+        assert_eq!(program.options.floor, Some(1));
+    }
+
+    fn macros(macros: &[(&str, &str)]) -> Context {
+        let mut context = Context::default();
+        for (name, expr) in macros {
+            context.macros.insert(name.to_string(), expr.to_string());
+        }
+        context
+    }
+
+    #[test]
+    fn macro_reference() {
+        let context = macros(&[("greatsword", "2d6 + 4")]);
+        let mut program = super::parse("#greatsword + 1d6", &context).unwrap();
+        assert_eq!(program.exprs[0].to_string(), "#greatsword+1d6");
+        match program.exprs.remove(0).kind {
+            ExprKind::BinOp { lhs, .. } => match lhs.kind {
+                ExprKind::Macro { name, expr } => {
+                    assert_eq!(name, "greatsword");
+                    assert_eq!(expr.to_string(), "2d6+4");
+                }
+                kind => panic!("expected a macro, got {:?}", kind),
+            },
+            kind => panic!("expected a binary operation, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn nested_macros() {
+        let context = macros(&[("a", "#b * 2"), ("b", "1d4")]);
+        assert!(super::parse("#a", &context).is_ok());
+    }
+
+    #[test]
+    fn unknown_macro() {
+        assert_eq!(
+            parse_expr("1 + #axe"),
+            Err(DiceError::UnknownMacro {
+                pos: 4,
+                name: "axe".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn recursive_macro() {
+        let context = macros(&[("a", "1 + #b"), ("b", "#a")]);
+        let err = super::parse("2 * #a", &context).unwrap_err();
+        assert_eq!(err.pos(), 4);
+        assert_eq!(
+            err.to_string(),
+            "in macro #a: in macro #b: macro #a refers to itself"
+        );
+    }
+
+    #[test]
+    fn modifiers_depend_on_spacing() {
+        let is_target = |m: &Modifier| matches!(m, Modifier::Target(_));
+        let is_explode = |m: &Modifier| matches!(m, Modifier::Explode(..));
+        let context = macros(&[("pool", "2d6>=7")]);
+        let has = |s, f: &dyn Fn(&Modifier) -> bool| {
+            super::parse(s, &context).unwrap().exprs[0].has_modifier(f)
+        };
+        assert!(has("2d6>=7", &is_target));
+        assert!(!has("2d6 >= 7", &is_target));
+        assert!(has("1 + #pool", &is_target));
+        assert!(has("1d6!=6", &is_explode));
+        assert!(!has("1d6 != 6", &is_explode));
+    }
+
+    #[test]
+    fn macro_bodies() {
+        let context = Context::default();
+        assert!(parse_macro("dmg", "2d6 + #str", &macros(&[("str", "3")])).is_ok());
+        assert!(parse_macro("dmg", "2d6-5; floor 1", &context).is_err());
+        assert!(parse_macro("stats", "6x 4d6kh3", &context).is_err());
+        assert!(parse_macro("pair", "{1d6, 1d6}", &context).is_err());
+        assert_eq!(
+            parse_macro("a", "1 + #a", &context)
+                .unwrap_err()
+                .to_string(),
+            "macro #a refers to itself"
+        );
+    }
+
+    #[test]
+    fn invalid_macro() {
+        let context = macros(&[("a", "1 +")]);
+        assert_eq!(
+            super::parse("#a", &context),
+            Err(DiceError::InMacro {
+                pos: 0,
+                name: "a".to_string(),
+                error: Box::new(DiceError::MissingOperand { pos: 2 })
+            })
+        );
+    }
+
+    #[test]
+    fn variables() {
+        let mut context = Context::default();
+        context.variables.insert("str_mod".to_string(), 3);
+        let program = super::parse("1d20 + @str_mod", &context).unwrap();
+        assert_eq!(program.exprs[0].to_string(), "1d20+@str_mod");
+        assert_eq!(
+            super::parse("1d20 + @str_mod + @prof", &context),
+            Err(DiceError::UnknownVariable {
+                pos: 18,
+                name: "prof".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn repetition() {
+        let program = parse("6x 4d6kh3").unwrap();
+        assert_eq!(program.repeat, 6);
+        assert_eq!(program.rolls().count(), 6);
+        assert_eq!(program.to_string(), "6x 4d6kh3");
+    }
+
+    #[test]
+    fn groups() {
+        let program = parse("{1d20 + 5, 1d20 + 3}; floor 1").unwrap();
+        assert_eq!(program.rolls().count(), 2);
+        assert_eq!(program.to_string(), "{1d20+5, 1d20+3}");
+        assert_eq!(parse_expr("2x {1d20}"), Ok("2x {1d20}".to_string()));
+    }
+
+    #[test]
+    fn malformed_groups() {
+        assert_eq!(
+            parse_expr("{1d20, 1d6"),
+            Err(DiceError::MismatchedBrace { pos: 0 })
+        );
+        assert_eq!(
+            parse_expr("{1d20} + 1"),
+            Err(DiceError::MissingOperator { pos: 7 })
+        );
+        assert_eq!(
+            parse_expr("1 + {1d20}"),
+            Err(DiceError::NestedGroup { pos: 4 })
+        );
+        assert_eq!(
+            parse_expr("2 * 3x 1d6"),
+            Err(DiceError::NestedGroup { pos: 4 })
+        );
+        assert_eq!(
+            parse_expr("0x 1d6"),
+            Err(DiceError::RollCount { pos: 0, count: 0 })
+        );
+        assert_eq!(
+            parse_expr("60x {1d6, 1d6}"),
+            Err(DiceError::RollCount { pos: 0, count: 120 })
+        );
+    }
+
+    #[test]
+    fn doubled_dice() {
+        let mut context = Context::default();
+        context
+            .macros
+            .insert("greatsword".to_string(), "2d6 + @str_mod".to_string());
+        context.variables.insert("str_mod".to_string(), 3);
+        let double = |s| double_dice(s, &context);
+        assert_eq!(double("2d6+3"), Ok("4d6+3".to_string()));
+        assert_eq!(double("#greatsword*2"), Ok("(4d6+@str_mod)*2".to_string()));
+        assert_eq!(
+            double("{1d8 + 1d6, 2x 1d4}").unwrap_err(),
+            DiceError::NestedGroup { pos: 12 }
+        );
+        assert_eq!(
+            double("2x 1d8!; floor 1"),
+            Ok("2x 2d8!; floor 1".to_string())
+        );
+        assert_eq!(double("max(1d6, 2)"), Ok("max(2d6,2)".to_string()));
+        assert_eq!(
+            double("1d20+5 >= 15 ? 2d6+3 : 0"),
+            Err(DiceError::ConditionalDamage { pos: 13 })
+        );
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_eq!(
+            parse_expr("1d20>=15"),
+            Ok("1d20>=15".to_string()),
+            "a comparison directly after a roll is a modifier"
+        );
+        assert_eq!(parse_expr("1d20+5>=15"), Ok("1d20+5 >= 15".to_string()));
+        assert_eq!(
+            parse_expr("1d20 + 5 >= 15 + 1"),
+            Ok("1d20+5 >= 15+1".to_string())
+        );
+        assert_eq!(parse_expr("1d6 = 6"), Ok("1d6 == 6".to_string()));
+        assert_eq!(
+            parse_expr("(1d6 > 2) + (1d6 > 2)"),
+            Ok("(1d6 > 2)+(1d6 > 2)".to_string())
+        );
+    }
+
+    #[test]
+    fn conditionals() {
+        assert_eq!(
+            parse_expr("1d20 + 5 >= 15 ? 2d6 + 3 : 0"),
+            Ok("1d20+5 >= 15 ? 2d6+3 : 0".to_string())
+        );
+        assert_eq!(
+            parse_expr("1d20 = 20 ? 2 : 1d20 = 1 ? 0 : 1"),
+            Ok("1d20 == 20 ? 2 : 1d20 == 1 ? 0 : 1".to_string())
+        );
+        assert_eq!(
+            parse_expr("(1 ? 2 : 3) ? 4 : 5"),
+            Ok("(1 ? 2 : 3) ? 4 : 5".to_string())
+        );
+        assert_eq!(
+            parse_expr("2 * (1d2 = 1 ? 3 : 4)"),
+            Ok("2*(1d2 == 1 ? 3 : 4)".to_string())
+        );
+    }
+
+    #[test]
+    fn malformed_conditionals() {
+        assert_eq!(
+            parse_expr("1 ? 2"),
+            Err(DiceError::ExpectedColon { pos: 5 })
+        );
+        assert_eq!(
+            parse_expr("1 ? 2 3"),
+            Err(DiceError::ExpectedColon { pos: 6 })
+        );
+        assert_eq!(
+            parse_expr("1 ? : 3"),
+            Err(DiceError::MissingOperand { pos: 4 })
+        );
+    }
+
+    #[test]
+    fn function_calls() {
+        assert_eq!(
+            parse_expr("max(1d6, 1d6) + floor( 1d20 / 2 )"),
+            Ok("max(1d6,1d6)+floor(1d20/2)".to_string())
+        );
+        assert_eq!(
+            parse_expr("-abs(min(1, -2, 3))"),
+            Ok("-abs(min(1,-2,3))".to_string())
+        );
+    }
+
+    #[test]
+    fn unknown_function() {
+        assert_eq!(
+            parse_expr("2 * sqrt(4)"),
+            Err(DiceError::UnknownFunction {
+                pos: 4,
+                name: "sqrt".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_calls() {
+        assert_eq!(
+            parse_expr("floor 1d20"),
+            Err(DiceError::ExpectedOpenParen { pos: 6 })
+        );
+        assert_eq!(
+            parse_expr("max(1, 2"),
+            Err(DiceError::MismatchedParen { pos: 3 })
+        );
+        assert_eq!(
+            parse_expr("max(1,)"),
+            Err(DiceError::MissingOperand { pos: 6 })
+        );
+        assert_eq!(
+            parse_expr("abs(1, 2)"),
+            Err(DiceError::WrongArgumentCount {
+                pos: 0,
+                function: Function::Abs,
+                count: 2
+            })
+        );
+        assert_eq!(
+            parse_expr("min()").unwrap_err().to_string(),
+            "min takes at least 1 argument, got 0"
+        );
+    }
+
+    #[test]
+    fn unmatched_close_paren() {
+        assert_eq!(
+            parse_expr("1 + 2)"),
+            Err(DiceError::MismatchedParen { pos: 5 })
+        );
+    }
+
+    #[test]
+    fn unmatched_open_paren() {
+        assert_eq!(
+            parse_expr("1 + (2 * 3"),
+            Err(DiceError::MismatchedParen { pos: 4 })
+        );
+        assert_eq!(parse_expr("("), Err(DiceError::MismatchedParen { pos: 0 }));
+    }
+
+    #[test]
+    fn missing_operand() {
+        assert_eq!(parse_expr("1 +"), Err(DiceError::MissingOperand { pos: 2 }));
+        assert_eq!(
+            parse_expr("1 + * 2"),
+            Err(DiceError::MissingOperand { pos: 4 })
+        );
+    }
+
+    #[test]
+    fn missing_operator() {
+        assert_eq!(
+            parse_expr("1 2"),
+            Err(DiceError::MissingOperator { pos: 2 })
+        );
+        assert_eq!(
+            parse_expr("(1 2)"),
+            Err(DiceError::MissingOperator { pos: 3 })
+        );
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(parse_expr("  "), Err(DiceError::UnexpectedEnd { pos: 2 }));
+    }
+}
@@ -0,0 +1,272 @@
+use super::parser::{BinOp, Expr, Function, ATOM_PRECEDENCE, COND_PRECEDENCE, PREFIX_PRECEDENCE};
+use enumset::{EnumSet, EnumSetType};
+use std::fmt;
+
+#[derive(EnumSetType, Debug)]
+pub enum DieFlag {
+    Dropped,
+    Rerolled,
+    Exploded,
+    Success,
+    Failure,
+    /// The die landed in the critical hit range.
+    Crit,
+    /// The die landed in the fumble range.
+    Fumble,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Die {
+    pub value: i64,
+    pub flags: EnumSet<DieFlag>,
+}
+
+impl Die {
+    pub fn new(value: i64) -> Self {
+        Die {
+            value,
+            flags: EnumSet::new(),
+        }
+    }
+
+    pub fn counts(&self) -> bool {
+        !self.flags.contains(DieFlag::Dropped) && !self.flags.contains(DieFlag::Rerolled)
+    }
+}
+
+impl fmt::Display for Die {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.counts() {
+            write!(f, "{}", self.value)?;
+        } else {
+            write!(f, "~{}~", self.value)?;
+        }
+        if self.flags.contains(DieFlag::Rerolled) {
+            write!(f, "r")?;
+        }
+        if self.flags.contains(DieFlag::Exploded) {
+            write!(f, "!")?;
+        }
+        if self.flags.contains(DieFlag::Success) {
+            write!(f, "*")?;
+        }
+        if self.flags.contains(DieFlag::Failure) {
+            write!(f, "f")?;
+        }
+        Ok(())
+    }
+}
+
+/// An evaluated expression, mirroring the shape of the parsed [`Expr`].
+/// Every node keeps the value it evaluated to, and rolls keep every die that
+/// was rolled, including the ones that were dropped or rerolled.
+#[derive(Debug, PartialEq)]
+pub enum Node {
+    Num(i64),
+    Roll {
+        dice: Vec<Die>,
+        value: i64,
+    },
+    Neg {
+        operand: Box<Node>,
+        value: i64,
+    },
+    BinOp {
+        op: BinOp,
+        lhs: Box<Node>,
+        rhs: Box<Node>,
+        value: i64,
+    },
+    Call {
+        function: Function,
+        args: Vec<Node>,
+        value: i64,
+    },
+    /// A conditional, keeping only the branch that was taken.
+    Cond {
+        test: Box<Node>,
+        holds: bool,
+        branch: Box<Node>,
+        value: i64,
+    },
+}
+
+impl Node {
+    pub fn value(&self) -> i64 {
+        match self {
+            Node::Num(n) => *n,
+            Node::Roll { value, .. } => *value,
+            Node::Neg { value, .. } => *value,
+            Node::BinOp { value, .. } => *value,
+            Node::Call { value, .. } => *value,
+            Node::Cond { value, .. } => *value,
+        }
+    }
+
+    /// Whether a die that counts has the flag, in this node or below it.
+    fn has_die(&self, flag: DieFlag) -> bool {
+        match self {
+            Node::Num(_) => false,
+            Node::Roll { dice, .. } => dice.iter().any(|d| d.counts() && d.flags.contains(flag)),
+            Node::Neg { operand, .. } => operand.has_die(flag),
+            Node::BinOp { lhs, rhs, .. } => lhs.has_die(flag) || rhs.has_die(flag),
+            Node::Call { args, .. } => args.iter().any(|a| a.has_die(flag)),
+            Node::Cond { test, branch, .. } => test.has_die(flag) || branch.has_die(flag),
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Node::Num(_) | Node::Roll { .. } | Node::Call { .. } => ATOM_PRECEDENCE,
+            Node::Neg { .. } => PREFIX_PRECEDENCE,
+            Node::BinOp { op, .. } => op.precedence(),
+            Node::Cond { .. } => COND_PRECEDENCE,
+        }
+    }
+}
+
+/// Formats the node with the rolled values of its dice, e.g.
+/// `([2] + 2) * 2`. The branch of a conditional that was not taken is shown
+/// as `...`.
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Node::Num(n) => write!(f, "{}", n),
+            Node::Roll { dice, .. } => {
+                let dice: Vec<String> = dice.iter().map(|d| d.to_string()).collect();
+                write!(f, "[{}]", dice.join(", "))
+            }
+            Node::Neg { operand, .. } => {
+                write!(f, "-")?;
+                write_operand(f, operand, operand.precedence() < PREFIX_PRECEDENCE)
+            }
+            Node::BinOp { op, lhs, rhs, .. } => {
+                write_operand(f, lhs, lhs.precedence() < op.precedence())?;
+                write!(f, " {} ", op)?;
+                write_operand(f, rhs, rhs.precedence() <= op.precedence())
+            }
+            Node::Call { function, args, .. } => {
+                let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+                write!(f, "{}({})", function, args.join(", "))
+            }
+            Node::Cond {
+                test,
+                holds,
+                branch,
+                ..
+            } => {
+                write_operand(f, test, test.precedence() <= COND_PRECEDENCE)?;
+                if *holds {
+                    write!(f, " ? {} : ...", branch)
+                } else {
+                    write!(f, " ? ... : {}", branch)
+                }
+            }
+        }
+    }
+}
+
+fn write_operand(f: &mut fmt::Formatter, node: &Node, parens: bool) -> fmt::Result {
+    if parens {
+        write!(f, "({})", node)
+    } else {
+        write!(f, "{}", node)
+    }
+}
+
+/// The result of evaluating a dice expression. The total is never below the
+/// floor of the expression, if it has one.
+#[derive(Debug, PartialEq)]
+pub struct RollResult {
+    pub expr: Expr,
+    pub rolled: Node,
+    pub floor: Option<i64>,
+    pub total: i64,
+}
+
+impl RollResult {
+    pub fn new(expr: Expr, rolled: Node, floor: Option<i64>) -> Self {
+        let value = rolled.value();
+        let total = floor.map_or(value, |floor| value.max(floor));
+        RollResult {
+            expr,
+            rolled,
+            floor,
+            total,
+        }
+    }
+
+    /// Whether a die that counts towards the total is a critical hit.
+    pub fn is_crit(&self) -> bool {
+        self.rolled.has_die(DieFlag::Crit)
+    }
+
+    /// Whether a die that counts towards the total is a fumble.
+    pub fn is_fumble(&self) -> bool {
+        self.rolled.has_die(DieFlag::Fumble)
+    }
+}
+
+/// Formats the result as `2d6+3 = [4, 5] + 3 = 12`, or as
+/// `1d4-5 = [2] - 5 = -3, floored to 1` when the floor was applied. Critical
+/// hits and fumbles are noted at the end, e.g. `1d20 = [20] = 20, critical
+/// hit`.
+impl fmt::Display for RollResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} = {} = {}",
+            self.expr,
+            self.rolled,
+            self.rolled.value()
+        )?;
+        if self.total != self.rolled.value() {
+            write!(f, ", floored to {}", self.total)?;
+        }
+        if self.is_crit() {
+            write!(f, ", critical hit")?;
+        }
+        if self.is_fumble() {
+            write!(f, ", fumble")?;
+        }
+        Ok(())
+    }
+}
+
+/// The results of one line of input: a single roll, or every roll of a
+/// repetition such as `6x 4d6kh3` or a group such as `{1d20, 1d20}`.
+#[derive(Debug, PartialEq)]
+pub struct Rolls {
+    pub notation: String,
+    pub results: Vec<RollResult>,
+    pub grouped: bool,
+}
+
+impl Rolls {
+    /// The total of every roll, e.g. `12, 7`.
+    pub fn totals(&self) -> String {
+        let totals: Vec<String> = self.results.iter().map(|r| r.total.to_string()).collect();
+        totals.join(", ")
+    }
+}
+
+/// Formats a single roll like its [`RollResult`]. Repetitions and groups list
+/// every total first, followed by the breakdown of each roll on its own line:
+///
+/// ```text
+/// {1d20, 1d20} = 12, 7
+///   1d20 = [12] = 12
+///   1d20 = [7] = 7
+/// ```
+impl fmt::Display for Rolls {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.grouped {
+            return self.results.iter().try_for_each(|r| write!(f, "{}", r));
+        }
+        write!(f, "{} = {}", self.notation, self.totals())?;
+        for result in &self.results {
+            write!(f, "\n  {}", result)?;
+        }
+        Ok(())
+    }
+}
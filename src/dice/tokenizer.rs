@@ -1,20 +1,229 @@
+use super::context::is_name_char;
+use super::error::DiceError;
 use crate::utils;
+use std::fmt;
 use std::iter::Peekable;
+use std::str::Chars;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Num(u32),
     Sym(char),
+    /// Unary minus.
+    Neg,
     OpenParen,
     CloseParen,
-    Roll { number: u32, sides: u32 },
+    Roll {
+        number: u32,
+        sides: Sides,
+        modifiers: Vec<Modifier>,
+    },
+    /// A reference to a named expression, e.g. `#greatsword`.
+    Macro(String),
+    /// A reference to an attribute of the selected character, e.g. `@str_mod`.
+    Var(String),
+    /// A function name, e.g. `max`.
+    Ident(String),
+    Comma,
+    /// A comparison between two expressions. Comparisons directly following
+    /// a roll are modifiers of the roll instead.
+    Cmp(CompareOp),
+    Question,
+    Colon,
+    /// Rolls what follows the given number of times, e.g. `6x`.
+    Repeat(u32),
+    OpenBrace,
+    CloseBrace,
 }
 
-pub fn tokenize(s: &str) -> Option<Vec<Token>> {
+/// The faces of a die.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Sides {
+    /// Faces numbered from 1, e.g. `d6`.
+    Numbered(u32),
+    /// Faces numbered 1 to 100 (`d%`).
+    Percentile,
+    /// Fate or Fudge dice, with faces -1, 0 and +1 (`dF`).
+    Fate,
+    /// Any list of faces, e.g. `d{1,1,2,3,5,8}`.
+    Faces(Vec<i64>),
+}
+
+impl Sides {
+    /// The number of faces.
+    pub fn count(&self) -> u32 {
+        match self {
+            Sides::Numbered(n) => *n,
+            Sides::Percentile => 100,
+            Sides::Fate => 3,
+            Sides::Faces(faces) => faces.len() as u32,
+        }
+    }
+
+    /// The value of the face at `index`, counting from 1.
+    pub fn face(&self, index: u32) -> i64 {
+        match self {
+            Sides::Numbered(_) | Sides::Percentile => index as i64,
+            Sides::Fate => index as i64 - 2,
+            Sides::Faces(faces) => faces[index as usize - 1],
+        }
+    }
+
+    pub fn faces(&self) -> impl Iterator<Item = i64> + '_ {
+        (1..=self.count()).map(move |i| self.face(i))
+    }
+
+    /// The highest face, which dice explode on by default.
+    pub fn max(&self) -> i64 {
+        match self {
+            Sides::Numbered(n) => *n as i64,
+            Sides::Percentile => 100,
+            Sides::Fate => 1,
+            Sides::Faces(faces) => faces.iter().copied().max().unwrap_or(0),
+        }
+    }
+}
+
+impl fmt::Display for Sides {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sides::Numbered(n) => write!(f, "{}", n),
+            Sides::Percentile => write!(f, "%"),
+            Sides::Fate => write!(f, "F"),
+            Sides::Faces(faces) => {
+                let faces: Vec<String> = faces.iter().map(|v| v.to_string()).collect();
+                write!(f, "{{{}}}", faces.join(","))
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Modifier {
+    KeepHighest(u32),
+    KeepLowest(u32),
+    DropHighest(u32),
+    DropLowest(u32),
+    Explode(Explode, Option<Compare>),
+    /// Reroll dice matching the comparison, either until they no longer
+    /// match or only once (`ro`).
+    Reroll {
+        once: bool,
+        compare: Compare,
+    },
+    /// Count the dice matching the comparison instead of summing them.
+    Target(Compare),
+    /// Dice matching the comparison subtract a success.
+    Failure(Compare),
+    /// Dice matching the comparison are critical hits (`cs`). Without it,
+    /// a d20 is a critical hit on a 20.
+    CritSuccess(Compare),
+    /// Dice matching the comparison are fumbles (`cf`). Without it, a d20
+    /// is a fumble on a 1.
+    CritFailure(Compare),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Explode {
+    /// Every exploding roll adds a new die (`!`).
+    Standard,
+    /// Exploding rolls are added to the die that exploded (`!!`).
+    Compound,
+    /// Like `Standard`, but every extra die is reduced by one (`!p`).
+    Penetrate,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Compare {
+    pub op: CompareOp,
+    pub value: i64,
+}
+
+impl Compare {
+    pub fn new(op: CompareOp, value: i64) -> Self {
+        Compare { op, value }
+    }
+
+    pub fn matches(&self, n: i64) -> bool {
+        match self.op {
+            CompareOp::Eq => n == self.value,
+            CompareOp::Ne => n != self.value,
+            CompareOp::Lt => n < self.value,
+            CompareOp::Le => n <= self.value,
+            CompareOp::Gt => n > self.value,
+            CompareOp::Ge => n >= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Modifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Modifier::KeepHighest(n) => write!(f, "kh{}", n),
+            Modifier::KeepLowest(n) => write!(f, "kl{}", n),
+            Modifier::DropHighest(n) => write!(f, "dh{}", n),
+            Modifier::DropLowest(n) => write!(f, "dl{}", n),
+            Modifier::Explode(explode, compare) => {
+                match explode {
+                    Explode::Standard => write!(f, "!")?,
+                    Explode::Compound => write!(f, "!!")?,
+                    Explode::Penetrate => write!(f, "!p")?,
+                }
+                match compare {
+                    Some(compare) => write!(f, "{}", compare),
+                    None => Ok(()),
+                }
+            }
+            Modifier::Reroll { once, compare } => {
+                let r = if *once { "ro" } else { "r" };
+                write!(f, "{}{}", r, compare)
+            }
+            Modifier::Target(compare) => write!(f, "{}", compare),
+            Modifier::Failure(compare) => write!(f, "f{}", compare),
+            Modifier::CritSuccess(compare) => write!(f, "cs{}", compare),
+            Modifier::CritFailure(compare) => write!(f, "cf{}", compare),
+        }
+    }
+}
+
+impl fmt::Display for Compare {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.op, self.value)
+    }
+}
+
+impl fmt::Display for CompareOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self {
+            CompareOp::Eq => "=",
+            CompareOp::Ne => "!=",
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">=",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+/// Splits the expression into tokens, each paired with the byte offset it
+/// starts at.
+pub fn tokenize(s: &str) -> Result<Vec<(usize, Token)>, DiceError> {
     let mut iterator = s.chars().peekable();
     let mut result = vec![];
 
     loop {
+        let pos = offset(s, &iterator);
         let c = iterator.peek().cloned();
         match c {
             Some(' ') => {
@@ -22,34 +231,87 @@ pub fn tokenize(s: &str) -> Option<Vec<Token>> {
                 continue;
             }
             Some('(') => {
-                result.push(Token::OpenParen);
+                result.push((pos, Token::OpenParen));
                 iterator.next();
             }
             Some(')') => {
-                result.push(Token::CloseParen);
+                result.push((pos, Token::CloseParen));
+                iterator.next();
+            }
+            Some('{') => {
+                result.push((pos, Token::OpenBrace));
+                iterator.next();
+            }
+            Some('}') => {
+                result.push((pos, Token::CloseBrace));
                 iterator.next();
             }
             Some('+') => {
-                result.push(Token::Sym('+'));
+                result.push((pos, Token::Sym('+')));
                 iterator.next();
             }
             Some('-') => {
-                result.push(Token::Sym('-'));
+                // a minus is unary unless it follows an operand
+                let token = match result.last() {
+                    Some((_, Token::Num(..)))
+                    | Some((_, Token::Roll { .. }))
+                    | Some((_, Token::Macro(..)))
+                    | Some((_, Token::Var(..)))
+                    | Some((_, Token::CloseParen)) => Token::Sym('-'),
+                    _ => Token::Neg,
+                };
+                result.push((pos, token));
                 iterator.next();
             }
             Some('*') => {
-                result.push(Token::Sym('*'));
+                result.push((pos, Token::Sym('*')));
                 iterator.next();
             }
             Some('/') => {
-                result.push(Token::Sym('/'));
+                result.push((pos, Token::Sym('/')));
+                iterator.next();
+            }
+            Some(',') => {
+                result.push((pos, Token::Comma));
+                iterator.next();
+            }
+            Some('?') => {
+                result.push((pos, Token::Question));
                 iterator.next();
             }
+            Some(':') => {
+                result.push((pos, Token::Colon));
+                iterator.next();
+            }
+            Some(ch @ '<') | Some(ch @ '>') | Some(ch @ '=') | Some(ch @ '!') => {
+                // a lone `!` has been consumed by now, so point at it
+                let op = consume_compare_op(&mut iterator)
+                    .ok_or(DiceError::UnexpectedCharacter { pos, ch })?;
+                result.push((pos, Token::Cmp(op)));
+            }
+            Some('d') if at_roll(&iterator) => match consume_roll(&mut iterator, 1) {
+                Some(token) => result.push((pos, token)),
+                None => return Err(unexpected(s, &mut iterator)),
+            },
+            Some(c) if c.is_ascii_alphabetic() => {
+                let name = utils::peek_while(&mut iterator, |c| is_name_char(*c)).collect();
+                result.push((pos, Token::Ident(name)));
+            }
+            Some('#') => {
+                iterator.next();
+                let name = consume_name(s, &mut iterator)?;
+                result.push((pos, Token::Macro(name)));
+            }
+            Some('@') => {
+                iterator.next();
+                let name = consume_name(s, &mut iterator)?;
+                result.push((pos, Token::Var(name)));
+            }
             Some(_) => {
                 if let Some(token) = consume_num_or_roll(&mut iterator) {
-                    result.push(token);
+                    result.push((pos, token));
                 } else {
-                    return None;
+                    return Err(unexpected(s, &mut iterator));
                 }
             }
             None => {
@@ -58,11 +320,82 @@ pub fn tokenize(s: &str) -> Option<Vec<Token>> {
         }
     }
 
-    Some(result)
+    Ok(result)
+}
+
+/// Options that apply to a whole expression, given after a `;`, e.g.
+/// `1d4 - 5; floor 1`.
+#[derive(Debug, PartialEq, Default)]
+pub struct Options {
+    /// The lowest total the expression may have.
+    pub floor: Option<i64>,
+}
+
+/// Parses the options following the `;` at byte offset `start` of `s`.
+pub fn tokenize_options(s: &str, start: usize) -> Result<Options, DiceError> {
+    let rest = &s[start + 1..];
+    let mut iterator = rest.chars().peekable();
+    let mut options = Options::default();
+    let pos = |iterator: &Peekable<Chars>| start + 1 + offset(rest, iterator);
+    let unexpected = |iterator: &mut Peekable<Chars>| {
+        let pos = pos(iterator);
+        match iterator.peek() {
+            Some(&ch) => DiceError::UnexpectedCharacter { pos, ch },
+            None => DiceError::UnexpectedEnd { pos },
+        }
+    };
+
+    loop {
+        utils::peek_while(&mut iterator, |c| *c == ' ').for_each(drop);
+        if iterator.peek().is_none() {
+            break;
+        }
+
+        let word_pos = pos(&iterator);
+        let word: String = utils::peek_while(&mut iterator, |c| c.is_ascii_alphabetic()).collect();
+        match word.as_str() {
+            "floor" => {
+                utils::peek_while(&mut iterator, |c| *c == ' ').for_each(drop);
+                let floor = consume_num(&mut iterator).ok_or_else(|| unexpected(&mut iterator))?;
+                options.floor = Some(floor as i64);
+            }
+            "" => return Err(unexpected(&mut iterator)),
+            _ => {
+                let ch = word.chars().next().unwrap();
+                return Err(DiceError::UnexpectedCharacter { pos: word_pos, ch });
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+/// The byte offset of the next character of the iterator into `s`.
+fn offset(s: &str, iter: &Peekable<Chars>) -> usize {
+    s.len() - iter.clone().map(char::len_utf8).sum::<usize>()
+}
+
+/// Consumes the name following a `#` or `@`, which must not be empty.
+fn consume_name(s: &str, iter: &mut Peekable<Chars>) -> Result<String, DiceError> {
+    let name: String = utils::peek_while(iter, |c| is_name_char(*c)).collect();
+    if name.is_empty() {
+        return Err(unexpected(s, iter));
+    }
+    Ok(name)
+}
+
+/// The error for the next character of the iterator, which could not be
+/// tokenized.
+fn unexpected(s: &str, iter: &mut Peekable<Chars>) -> DiceError {
+    let pos = offset(s, iter);
+    match iter.peek() {
+        Some(&ch) => DiceError::UnexpectedCharacter { pos, ch },
+        None => DiceError::UnexpectedEnd { pos },
+    }
 }
 
 fn consume_num(iter: &mut Peekable<impl Iterator<Item = char>>) -> Option<u32> {
-    utils::peek_while(iter, |c| c.is_digit(10))
+    utils::peek_while(iter, |c| c.is_ascii_digit())
         .collect::<String>()
         .parse::<u32>()
         .ok()
@@ -71,22 +404,235 @@ fn consume_num(iter: &mut Peekable<impl Iterator<Item = char>>) -> Option<u32> {
 fn consume_num_or_roll(iter: &mut Peekable<impl Iterator<Item = char>>) -> Option<Token> {
     let n1 = consume_num(iter)?;
 
-    if let Some('d') = iter.peek() {
+    match iter.peek() {
+        Some('d') => consume_roll(iter, n1),
+        Some('x') => {
+            iter.next();
+            Some(Token::Repeat(n1))
+        }
+        _ => Some(Token::Num(n1)),
+    }
+}
+
+/// Whether the iterator is at a roll without a number of dice, e.g. `dF`,
+/// which is a single die.
+fn at_roll(iter: &Peekable<impl Iterator<Item = char> + Clone>) -> bool {
+    let mut iter = iter.clone();
+    iter.next() == Some('d')
+        && matches!(iter.next(), Some(c) if c.is_ascii_digit() || "%F{".contains(c))
+}
+
+/// Consumes a roll of `number` dice, starting at the `d`.
+fn consume_roll(iter: &mut Peekable<impl Iterator<Item = char>>, number: u32) -> Option<Token> {
+    iter.next();
+    let sides = match iter.peek() {
+        Some('%') => {
+            iter.next();
+            Sides::Percentile
+        }
+        Some('F') => {
+            iter.next();
+            Sides::Fate
+        }
+        Some('{') => {
+            iter.next();
+            Sides::Faces(consume_faces(iter)?)
+        }
+        _ => Sides::Numbered(consume_num(iter)?),
+    };
+    let modifiers = consume_modifiers(iter)?;
+    Some(Token::Roll {
+        number,
+        sides,
+        modifiers,
+    })
+}
+
+/// Consumes a comma separated list of faces up to and including the closing
+/// brace. Faces may be negative.
+fn consume_faces(iter: &mut Peekable<impl Iterator<Item = char>>) -> Option<Vec<i64>> {
+    let mut faces = vec![];
+
+    loop {
+        utils::peek_while(iter, |c| *c == ' ').for_each(drop);
+        let negative = iter.peek() == Some(&'-');
+        if negative {
+            iter.next();
+        }
+        let face = consume_num(iter)? as i64;
+        faces.push(if negative { -face } else { face });
+        utils::peek_while(iter, |c| *c == ' ').for_each(drop);
+
+        match iter.next() {
+            Some(',') => continue,
+            Some('}') => return Some(faces),
+            _ => return None,
+        }
+    }
+}
+
+fn consume_modifiers(iter: &mut Peekable<impl Iterator<Item = char>>) -> Option<Vec<Modifier>> {
+    let mut modifiers = vec![];
+
+    loop {
+        let modifier: fn(u32) -> Modifier = match iter.peek() {
+            Some('!') => {
+                iter.next();
+                let explode = match iter.peek() {
+                    Some('!') => {
+                        iter.next();
+                        Explode::Compound
+                    }
+                    Some('p') => {
+                        iter.next();
+                        Explode::Penetrate
+                    }
+                    _ => Explode::Standard,
+                };
+                let compare = consume_compare(iter)?;
+                modifiers.push(Modifier::Explode(explode, compare));
+                continue;
+            }
+            Some('<') | Some('>') | Some('=') => {
+                let compare = consume_compare(iter)??;
+                modifiers.push(Modifier::Target(compare));
+                continue;
+            }
+            Some('r') => {
+                iter.next();
+                let once = if let Some('o') = iter.peek() {
+                    iter.next();
+                    true
+                } else {
+                    false
+                };
+                let compare = consume_compare(iter)??;
+                modifiers.push(Modifier::Reroll { once, compare });
+                continue;
+            }
+            Some('f') => {
+                iter.next();
+                let compare = consume_compare(iter)??;
+                modifiers.push(Modifier::Failure(compare));
+                continue;
+            }
+            Some('c') => {
+                iter.next();
+                let modifier: fn(Compare) -> Modifier = match iter.next() {
+                    Some('s') => Modifier::CritSuccess,
+                    Some('f') => Modifier::CritFailure,
+                    _ => return None,
+                };
+                let compare = consume_compare(iter)??;
+                modifiers.push(modifier(compare));
+                continue;
+            }
+            Some('k') => {
+                iter.next();
+                match iter.peek() {
+                    Some('l') => {
+                        iter.next();
+                        Modifier::KeepLowest
+                    }
+                    Some('h') => {
+                        iter.next();
+                        Modifier::KeepHighest
+                    }
+                    // plain `k` is shorthand for `kh`
+                    _ => Modifier::KeepHighest,
+                }
+            }
+            Some('d') => {
+                iter.next();
+                match iter.peek() {
+                    Some('h') => {
+                        iter.next();
+                        Modifier::DropHighest
+                    }
+                    Some('l') => {
+                        iter.next();
+                        Modifier::DropLowest
+                    }
+                    // plain `d` is shorthand for `dl`
+                    _ => Modifier::DropLowest,
+                }
+            }
+            _ => break,
+        };
+
+        let count = consume_num(iter).unwrap_or(1);
+        modifiers.push(modifier(count));
+    }
+
+    Some(modifiers)
+}
+
+/// Consumes a comparison between expressions: `=` (or `==`), `!=`, `<`,
+/// `<=`, `>` or `>=`.
+fn consume_compare_op(iter: &mut Peekable<impl Iterator<Item = char>>) -> Option<CompareOp> {
+    let first = iter.next()?;
+    let equals = iter.peek() == Some(&'=');
+    if equals {
         iter.next();
-        let n2 = consume_num(iter)?;
-        return Some(Token::Roll {
-            number: n1,
-            sides: n2,
-        });
     }
+    match (first, equals) {
+        ('=', _) => Some(CompareOp::Eq),
+        ('!', true) => Some(CompareOp::Ne),
+        ('<', false) => Some(CompareOp::Lt),
+        ('<', true) => Some(CompareOp::Le),
+        ('>', false) => Some(CompareOp::Gt),
+        ('>', true) => Some(CompareOp::Ge),
+        _ => None,
+    }
+}
+
+/// Consumes an optional comparison such as `>=7` or `=1`. Returns `None` if
+/// a comparison operator is not followed by a number.
+fn consume_compare(iter: &mut Peekable<impl Iterator<Item = char>>) -> Option<Option<Compare>> {
+    let op = match iter.peek() {
+        Some('=') => {
+            iter.next();
+            CompareOp::Eq
+        }
+        Some('<') => {
+            iter.next();
+            if let Some('=') = iter.peek() {
+                iter.next();
+                CompareOp::Le
+            } else {
+                CompareOp::Lt
+            }
+        }
+        Some('>') => {
+            iter.next();
+            if let Some('=') = iter.peek() {
+                iter.next();
+                CompareOp::Ge
+            } else {
+                CompareOp::Gt
+            }
+        }
+        Some(c) if c.is_ascii_digit() => CompareOp::Eq,
+        _ => return Some(None),
+    };
 
-    return Some(Token::Num(n1));
+    let value = consume_num(iter)?;
+    Some(Some(Compare::new(op, value as i64)))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn highest_face() {
+        assert_eq!(Sides::Numbered(20).max(), 20);
+        assert_eq!(Sides::Numbered(u32::MAX).max(), u32::MAX as i64);
+        assert_eq!(Sides::Percentile.max(), 100);
+        assert_eq!(Sides::Fate.max(), 1);
+        assert_eq!(Sides::Faces(vec![3, -1, 5, 0]).max(), 5);
+    }
+
     mod consume_num_or_roll {
         use super::*;
 
@@ -106,7 +652,8 @@ mod test {
                 result,
                 Some(Token::Roll {
                     number: 2,
-                    sides: 6
+                    sides: Sides::Numbered(6),
+                    modifiers: vec![],
                 })
             );
             assert_eq!(iter.next(), None);
@@ -120,12 +667,246 @@ mod test {
                 result,
                 Some(Token::Roll {
                     number: 2,
-                    sides: 6
+                    sides: Sides::Numbered(6),
+                    modifiers: vec![],
+                })
+            );
+            assert_eq!(iter.collect::<String>(), "abc");
+        }
+
+        #[test]
+        fn keep_highest() {
+            let mut iter = "4d6kh3".chars().peekable();
+            let result = consume_num_or_roll(&mut iter);
+            assert_eq!(
+                result,
+                Some(Token::Roll {
+                    number: 4,
+                    sides: Sides::Numbered(6),
+                    modifiers: vec![Modifier::KeepHighest(3)],
+                })
+            );
+            assert_eq!(iter.next(), None);
+        }
+
+        #[test]
+        fn keep_lowest() {
+            let mut iter = "2d20kl1".chars().peekable();
+            let result = consume_num_or_roll(&mut iter);
+            assert_eq!(
+                result,
+                Some(Token::Roll {
+                    number: 2,
+                    sides: Sides::Numbered(20),
+                    modifiers: vec![Modifier::KeepLowest(1)],
+                })
+            );
+            assert_eq!(iter.next(), None);
+        }
+
+        #[test]
+        fn drop_shorthand() {
+            let mut iter = "4d6d".chars().peekable();
+            let result = consume_num_or_roll(&mut iter);
+            assert_eq!(
+                result,
+                Some(Token::Roll {
+                    number: 4,
+                    sides: Sides::Numbered(6),
+                    modifiers: vec![Modifier::DropLowest(1)],
+                })
+            );
+            assert_eq!(iter.next(), None);
+        }
+
+        #[test]
+        fn drop_highest_with_junk() {
+            let mut iter = "3d8dh1abc".chars().peekable();
+            let result = consume_num_or_roll(&mut iter);
+            assert_eq!(
+                result,
+                Some(Token::Roll {
+                    number: 3,
+                    sides: Sides::Numbered(8),
+                    modifiers: vec![Modifier::DropHighest(1)],
                 })
             );
             assert_eq!(iter.collect::<String>(), "abc");
         }
 
+        #[test]
+        fn explode() {
+            let mut iter = "1d6!".chars().peekable();
+            let result = consume_num_or_roll(&mut iter);
+            assert_eq!(
+                result,
+                Some(Token::Roll {
+                    number: 1,
+                    sides: Sides::Numbered(6),
+                    modifiers: vec![Modifier::Explode(Explode::Standard, None)],
+                })
+            );
+            assert_eq!(iter.next(), None);
+        }
+
+        #[test]
+        fn explode_with_compare() {
+            let mut iter = "1d10!>8".chars().peekable();
+            let result = consume_num_or_roll(&mut iter);
+            assert_eq!(
+                result,
+                Some(Token::Roll {
+                    number: 1,
+                    sides: Sides::Numbered(10),
+                    modifiers: vec![Modifier::Explode(
+                        Explode::Standard,
+                        Some(Compare::new(CompareOp::Gt, 8))
+                    )],
+                })
+            );
+            assert_eq!(iter.next(), None);
+        }
+
+        #[test]
+        fn compound_and_keep() {
+            let mut iter = "2d6!!kh1".chars().peekable();
+            let result = consume_num_or_roll(&mut iter);
+            assert_eq!(
+                result,
+                Some(Token::Roll {
+                    number: 2,
+                    sides: Sides::Numbered(6),
+                    modifiers: vec![
+                        Modifier::Explode(Explode::Compound, None),
+                        Modifier::KeepHighest(1)
+                    ],
+                })
+            );
+            assert_eq!(iter.next(), None);
+        }
+
+        #[test]
+        fn penetrate() {
+            let mut iter = "1d6!p".chars().peekable();
+            let result = consume_num_or_roll(&mut iter);
+            assert_eq!(
+                result,
+                Some(Token::Roll {
+                    number: 1,
+                    sides: Sides::Numbered(6),
+                    modifiers: vec![Modifier::Explode(Explode::Penetrate, None)],
+                })
+            );
+            assert_eq!(iter.next(), None);
+        }
+
+        #[test]
+        fn explode_missing_compare_value() {
+            let mut iter = "1d6!>".chars().peekable();
+            assert_eq!(consume_num_or_roll(&mut iter), None);
+        }
+
+        #[test]
+        fn success_pool() {
+            let mut iter = "10d10>=7f1".chars().peekable();
+            let result = consume_num_or_roll(&mut iter);
+            assert_eq!(
+                result,
+                Some(Token::Roll {
+                    number: 10,
+                    sides: Sides::Numbered(10),
+                    modifiers: vec![
+                        Modifier::Target(Compare::new(CompareOp::Ge, 7)),
+                        Modifier::Failure(Compare::new(CompareOp::Eq, 1))
+                    ],
+                })
+            );
+            assert_eq!(iter.next(), None);
+        }
+
+        #[test]
+        fn crit_ranges() {
+            let mut iter = "1d20cs>=19cf2".chars().peekable();
+            let result = consume_num_or_roll(&mut iter);
+            assert_eq!(
+                result,
+                Some(Token::Roll {
+                    number: 1,
+                    sides: Sides::Numbered(20),
+                    modifiers: vec![
+                        Modifier::CritSuccess(Compare::new(CompareOp::Ge, 19)),
+                        Modifier::CritFailure(Compare::new(CompareOp::Eq, 2))
+                    ],
+                })
+            );
+            assert_eq!(iter.next(), None);
+        }
+
+        #[test]
+        fn crit_range_without_comparison() {
+            let mut iter = "1d20cs".chars().peekable();
+            assert_eq!(consume_num_or_roll(&mut iter), None);
+            let mut iter = "1d20cx19".chars().peekable();
+            assert_eq!(consume_num_or_roll(&mut iter), None);
+        }
+
+        #[test]
+        fn success_pool_less_than() {
+            let mut iter = "5d6<3".chars().peekable();
+            let result = consume_num_or_roll(&mut iter);
+            assert_eq!(
+                result,
+                Some(Token::Roll {
+                    number: 5,
+                    sides: Sides::Numbered(6),
+                    modifiers: vec![Modifier::Target(Compare::new(CompareOp::Lt, 3))],
+                })
+            );
+            assert_eq!(iter.next(), None);
+        }
+
+        #[test]
+        fn failure_without_value() {
+            let mut iter = "10d10>=7f".chars().peekable();
+            assert_eq!(consume_num_or_roll(&mut iter), None);
+        }
+
+        #[test]
+        fn reroll() {
+            let mut iter = "2d6r1".chars().peekable();
+            let result = consume_num_or_roll(&mut iter);
+            assert_eq!(
+                result,
+                Some(Token::Roll {
+                    number: 2,
+                    sides: Sides::Numbered(6),
+                    modifiers: vec![Modifier::Reroll {
+                        once: false,
+                        compare: Compare::new(CompareOp::Eq, 1)
+                    }],
+                })
+            );
+            assert_eq!(iter.next(), None);
+        }
+
+        #[test]
+        fn reroll_once() {
+            let mut iter = "2d6ro<3".chars().peekable();
+            let result = consume_num_or_roll(&mut iter);
+            assert_eq!(
+                result,
+                Some(Token::Roll {
+                    number: 2,
+                    sides: Sides::Numbered(6),
+                    modifiers: vec![Modifier::Reroll {
+                        once: true,
+                        compare: Compare::new(CompareOp::Lt, 3)
+                    }],
+                })
+            );
+            assert_eq!(iter.next(), None);
+        }
+
         #[test]
         fn junk() {
             let mut iter = "x22".chars().peekable();
@@ -138,35 +919,171 @@ mod test {
     mod tokenize {
         use super::*;
 
+        fn tokens(s: &str) -> Result<Vec<Token>, DiceError> {
+            tokenize(s).map(|tokens| tokens.into_iter().map(|(_, t)| t).collect())
+        }
+
         #[test]
         fn roll() {
             let s = "2d6";
             let expected = vec![Token::Roll {
                 number: 2,
-                sides: 6,
+                sides: Sides::Numbered(6),
+                modifiers: vec![],
             }];
-            assert_eq!(tokenize(s), Some(expected));
+            assert_eq!(tokens(s), Ok(expected));
         }
 
         #[test]
         fn addition() {
             let s = "1 + 2";
             let expected = vec![Token::Num(1), Token::Sym('+'), Token::Num(2)];
-            assert_eq!(tokenize(s), Some(expected));
+            assert_eq!(tokens(s), Ok(expected));
         }
 
         #[test]
         fn subtraction() {
             let s = "1 - 2";
             let expected = vec![Token::Num(1), Token::Sym('-'), Token::Num(2)];
-            assert_eq!(tokenize(s), Some(expected));
+            assert_eq!(tokens(s), Ok(expected));
+        }
+
+        #[test]
+        fn comparisons() {
+            let s = "1d20+5 >= 15 ? 1 : 0";
+            let expected = vec![
+                Token::Roll {
+                    number: 1,
+                    sides: Sides::Numbered(20),
+                    modifiers: vec![],
+                },
+                Token::Sym('+'),
+                Token::Num(5),
+                Token::Cmp(CompareOp::Ge),
+                Token::Num(15),
+                Token::Question,
+                Token::Num(1),
+                Token::Colon,
+                Token::Num(0),
+            ];
+            assert_eq!(tokens(s), Ok(expected));
+
+            let ops: Vec<Token> = tokens("1 == 1 = 1 != 1 < 1 <= 1 > 1")
+                .unwrap()
+                .into_iter()
+                .filter(|t| *t != Token::Num(1))
+                .collect();
+            let expected: Vec<Token> = vec![
+                CompareOp::Eq,
+                CompareOp::Eq,
+                CompareOp::Ne,
+                CompareOp::Lt,
+                CompareOp::Le,
+                CompareOp::Gt,
+            ]
+            .into_iter()
+            .map(Token::Cmp)
+            .collect();
+            assert_eq!(ops, expected);
+        }
+
+        #[test]
+        fn comparison_after_roll_is_modifier() {
+            let s = "1d20 >= 15";
+            assert_eq!(tokens(s).unwrap().len(), 3);
+            let s = "1d20>=15";
+            assert_eq!(tokens(s).unwrap().len(), 1);
+        }
+
+        #[test]
+        fn lone_exclamation_mark() {
+            assert_eq!(
+                tokens("1 ! 2"),
+                Err(DiceError::UnexpectedCharacter { pos: 2, ch: '!' })
+            );
+        }
+
+        #[test]
+        fn repetition() {
+            let s = "6x 4d6kh3";
+            let expected = vec![
+                Token::Repeat(6),
+                Token::Roll {
+                    number: 4,
+                    sides: Sides::Numbered(6),
+                    modifiers: vec![Modifier::KeepHighest(3)],
+                },
+            ];
+            assert_eq!(tokens(s), Ok(expected));
+        }
+
+        #[test]
+        fn group() {
+            let s = "{1, 2d{1,2}}";
+            let expected = vec![
+                Token::OpenBrace,
+                Token::Num(1),
+                Token::Comma,
+                Token::Roll {
+                    number: 2,
+                    sides: Sides::Faces(vec![1, 2]),
+                    modifiers: vec![],
+                },
+                Token::CloseBrace,
+            ];
+            assert_eq!(tokens(s), Ok(expected));
+        }
+
+        #[test]
+        fn special_dice() {
+            let s = "4dF + d% + 2d{1, -1,0}";
+            let expected = vec![
+                Token::Roll {
+                    number: 4,
+                    sides: Sides::Fate,
+                    modifiers: vec![],
+                },
+                Token::Sym('+'),
+                Token::Roll {
+                    number: 1,
+                    sides: Sides::Percentile,
+                    modifiers: vec![],
+                },
+                Token::Sym('+'),
+                Token::Roll {
+                    number: 2,
+                    sides: Sides::Faces(vec![1, -1, 0]),
+                    modifiers: vec![],
+                },
+            ];
+            assert_eq!(tokens(s), Ok(expected));
+        }
+
+        #[test]
+        fn single_die_without_number() {
+            let s = "d20";
+            let expected = vec![Token::Roll {
+                number: 1,
+                sides: Sides::Numbered(20),
+                modifiers: vec![],
+            }];
+            assert_eq!(tokens(s), Ok(expected));
+        }
+
+        #[test]
+        fn malformed_faces() {
+            assert_eq!(
+                tokens("1d{}"),
+                Err(DiceError::UnexpectedCharacter { pos: 3, ch: '}' })
+            );
+            assert_eq!(tokens("1d{1,2"), Err(DiceError::UnexpectedEnd { pos: 6 }));
         }
 
         #[test]
         fn nospace() {
             let s = "1+2";
             let expected = vec![Token::Num(1), Token::Sym('+'), Token::Num(2)];
-            assert_eq!(tokenize(s), Some(expected));
+            assert_eq!(tokens(s), Ok(expected));
         }
 
         #[test]
@@ -175,12 +1092,13 @@ mod test {
             let expected = vec![
                 Token::Roll {
                     number: 2,
-                    sides: 6,
+                    sides: Sides::Numbered(6),
+                    modifiers: vec![],
                 },
                 Token::Sym('+'),
                 Token::Num(1),
             ];
-            assert_eq!(tokenize(s), Some(expected));
+            assert_eq!(tokens(s), Ok(expected));
         }
 
         #[test]
@@ -193,7 +1111,142 @@ mod test {
                 Token::Num(2),
                 Token::CloseParen,
             ];
-            assert_eq!(tokenize(s), Some(expected));
+            assert_eq!(tokens(s), Ok(expected));
+        }
+
+        #[test]
+        fn unary_minus() {
+            let s = "-2 + -(1d6) - 1";
+            let expected = vec![
+                Token::Neg,
+                Token::Num(2),
+                Token::Sym('+'),
+                Token::Neg,
+                Token::OpenParen,
+                Token::Roll {
+                    number: 1,
+                    sides: Sides::Numbered(6),
+                    modifiers: vec![],
+                },
+                Token::CloseParen,
+                Token::Sym('-'),
+                Token::Num(1),
+            ];
+            assert_eq!(tokens(s), Ok(expected));
+        }
+
+        #[test]
+        fn macro_reference() {
+            let s = "#great_sword2 - 1";
+            let expected = vec![
+                Token::Macro("great_sword2".to_string()),
+                Token::Sym('-'),
+                Token::Num(1),
+            ];
+            assert_eq!(tokens(s), Ok(expected));
+        }
+
+        #[test]
+        fn variables() {
+            let s = "1d20 + @str_mod + @prof";
+            let expected = vec![
+                Token::Roll {
+                    number: 1,
+                    sides: Sides::Numbered(20),
+                    modifiers: vec![],
+                },
+                Token::Sym('+'),
+                Token::Var("str_mod".to_string()),
+                Token::Sym('+'),
+                Token::Var("prof".to_string()),
+            ];
+            assert_eq!(tokens(s), Ok(expected));
+        }
+
+        #[test]
+        fn macro_without_name() {
+            assert_eq!(
+                tokens("# + 1"),
+                Err(DiceError::UnexpectedCharacter { pos: 1, ch: ' ' })
+            );
+            assert_eq!(tokens("1 + #"), Err(DiceError::UnexpectedEnd { pos: 5 }));
+        }
+
+        #[test]
+        fn positions() {
+            let positions: Vec<usize> = tokenize("2d6 + (10)")
+                .unwrap()
+                .into_iter()
+                .map(|(pos, _)| pos)
+                .collect();
+            assert_eq!(positions, vec![0, 4, 6, 7, 9]);
+        }
+
+        #[test]
+        fn function_call() {
+            let s = "max(1, 2)";
+            let expected = vec![
+                Token::Ident("max".to_string()),
+                Token::OpenParen,
+                Token::Num(1),
+                Token::Comma,
+                Token::Num(2),
+                Token::CloseParen,
+            ];
+            assert_eq!(tokens(s), Ok(expected));
+        }
+
+        #[test]
+        fn unexpected_character() {
+            assert_eq!(
+                tokens("2d6 + $"),
+                Err(DiceError::UnexpectedCharacter { pos: 6, ch: '$' })
+            );
+        }
+
+        #[test]
+        fn unexpected_character_in_modifier() {
+            assert_eq!(
+                tokens("1d6!>x"),
+                Err(DiceError::UnexpectedCharacter { pos: 5, ch: 'x' })
+            );
+        }
+
+        #[test]
+        fn unexpected_end() {
+            assert_eq!(tokens("1 + 2d"), Err(DiceError::UnexpectedEnd { pos: 6 }));
+        }
+    }
+
+    mod tokenize_options {
+        use super::*;
+
+        #[test]
+        fn floor() {
+            let s = "1d4 - 5; floor 1";
+            let expected = Options { floor: Some(1) };
+            assert_eq!(tokenize_options(s, 7), Ok(expected));
+        }
+
+        #[test]
+        fn empty() {
+            assert_eq!(tokenize_options("1d4;  ", 3), Ok(Options::default()));
+        }
+
+        #[test]
+        fn unknown_option() {
+            assert_eq!(
+                tokenize_options("1d4; ceil 3", 3),
+                Err(DiceError::UnexpectedCharacter { pos: 5, ch: 'c' })
+            );
+        }
+
+        #[test]
+        fn missing_floor_value() {
+            assert_eq!(
+                tokenize_options("1d4; floor", 3),
+                Err(DiceError::UnexpectedEnd { pos: 10 })
+            );
         }
     }
 }
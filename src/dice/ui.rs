@@ -1,35 +1,342 @@
+use super::tokenizer::Modifier;
 use crate::dice;
+use crate::dice::{DiceError, Distribution, Rolls};
 use crate::ui;
 use crate::ui::ControllerMessage;
+use cursive::theme::PaletteColor;
+use cursive::traits::*;
+use cursive::utils::markup::StyledString;
 use cursive::views::*;
 use cursive::Cursive;
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::sync::mpsc::Sender;
 
+/// Asks for an expression to roll, remembering the ones rolled during the
+/// session so that they can be recalled with Up/Down or rolled again.
+#[derive(Clone)]
 pub struct RollDiceDialog {
     tx: Sender<ControllerMessage>,
+    history: Rc<RefCell<Vec<String>>>,
 }
 
 impl RollDiceDialog {
     pub fn new(tx: &Sender<ControllerMessage>) -> Self {
-        RollDiceDialog { tx: tx.clone() }
+        RollDiceDialog {
+            tx: tx.clone(),
+            history: Default::default(),
+        }
     }
 
     pub fn show(&self, cursive: &mut Cursive) {
         let tx = self.tx.clone();
-        let dialog = ui::build_input_dialog("Roll dice", None, move |cursive, input| {
-            let result = dice::eval(input);
-            match result {
-                Some(num) => {
-                    let msg = format!("Rolling: {} -> {:?}", input, num);
-                    tx.send(ControllerMessage::LogMessage(msg)).unwrap();
-                    cursive.pop_layer();
-                }
-                None => {
-                    let mut view = cursive.find_name::<TextView>("input_msg").unwrap();
-                    view.set_content("meh");
+        let entries = self.history.borrow().clone();
+        let message = Some(ROLL_HELP.to_string());
+        let dialog =
+            ui::build_history_input_dialog("Roll dice", message, entries, move |cursive, input| {
+                // the controller owns the dice roller, so only check that the
+                // expression is valid here
+                match dice::parse(input, &context(cursive)) {
+                    Ok(_) => {
+                        tx.send(ControllerMessage::Roll(input.to_string())).unwrap();
+                        cursive.pop_layer();
+                    }
+                    Err(err) => {
+                        let mut view = cursive.find_name::<TextView>("input_msg").unwrap();
+                        view.set_content(format_error(input, &err));
+                    }
+                };
+            });
+        cursive.add_layer(dialog);
+        cursive.call_on_name("input_field", |view: &mut EditView| {
+            view.set_on_edit(|cursive, input, _| {
+                let preview = format_preview(input, &context(cursive));
+                let mut view = cursive.find_name::<TextView>("input_msg").unwrap();
+                view.set_content(preview);
+            });
+        });
+    }
+
+    /// Adds an expression that was rolled to the history, unless it was
+    /// also the last one.
+    pub fn record(&self, input: &str) {
+        let mut history = self.history.borrow_mut();
+        if history.last().map(String::as_str) != Some(input) {
+            history.push(input.to_string());
+        }
+    }
+
+    /// Rolls the last expression rolled again, whether through the dialog,
+    /// a macro or a critical hit.
+    pub fn reroll_last(&self, cursive: &mut Cursive) {
+        match self.history.borrow().last() {
+            Some(input) => self
+                .tx
+                .send(ControllerMessage::Roll(input.clone()))
+                .unwrap(),
+            None => cursive.add_layer(Dialog::info("Nothing rolled yet, press r to roll.")),
+        }
+    }
+}
+
+/// Lists the macros of the campaign, rolling the one picked.
+pub fn show_macros_dialog(cursive: &mut Cursive, tx: &Sender<ControllerMessage>) {
+    let macros = context(cursive).macros;
+    if macros.is_empty() {
+        cursive.add_layer(Dialog::info("No macros yet, press M to define one."));
+        return;
+    }
+
+    let longest_name = macros.keys().map(|n| n.chars().count()).max().unwrap_or(0);
+    let mut list = SelectView::<String>::new();
+    for (name, expr) in macros {
+        let label = format!("#{:<width$}  {}", name, expr, width = longest_name);
+        list.add_item(label, name);
+    }
+
+    let tx = tx.clone();
+    list.set_on_submit(move |cursive, name: &str| {
+        tx.send(ControllerMessage::Roll(format!("#{}", name)))
+            .unwrap();
+        cursive.pop_layer();
+    });
+
+    let dialog = Dialog::around(list.scrollable())
+        .title("Macros")
+        .dismiss_button("Close");
+    cursive.add_layer(dialog);
+}
+
+/// Asks for a macro definition such as `greatsword = 2d6+4`. An empty
+/// expression removes the macro.
+pub fn show_define_macro_dialog(cursive: &mut Cursive, tx: &Sender<ControllerMessage>) {
+    let tx = tx.clone();
+    let message = Some("name = expression".to_string());
+    let dialog = ui::build_input_dialog("Define macro", message, move |cursive, input| {
+        let msg = match dice::parse_definition(input, '#') {
+            Ok((name, expr)) => {
+                // check the expression as it would be used, so that options,
+                // groups and macros referring to themselves are caught
+                match dice::parse_macro(&name, &expr, &context(cursive)) {
+                    Err(err) if !expr.is_empty() => format_error(&expr, &err),
+                    _ => {
+                        tx.send(ControllerMessage::DefineMacro { name, expr })
+                            .unwrap();
+                        cursive.pop_layer();
+                        return;
+                    }
                 }
+            }
+            Err(msg) => msg,
+        };
+        let mut view = cursive.find_name::<TextView>("input_msg").unwrap();
+        view.set_content(msg);
+    });
+    cursive.add_layer(dialog);
+}
+
+/// Offers to roll the damage of a critical hit with twice as many dice.
+/// Submitting nothing skips it.
+pub fn show_critical_damage_dialog(cursive: &mut Cursive, tx: &Sender<ControllerMessage>) {
+    let tx = tx.clone();
+    let message = Some("Damage to roll with doubled dice,\nor nothing to skip".to_string());
+    let dialog = ui::build_input_dialog("Critical hit!", message, move |cursive, input| {
+        if input.trim().is_empty() {
+            cursive.pop_layer();
+            return;
+        }
+        match dice::double_dice(input, &context(cursive)) {
+            Ok(doubled) => {
+                tx.send(ControllerMessage::Roll(doubled)).unwrap();
+                cursive.pop_layer();
+            }
+            Err(err) => {
+                let mut view = cursive.find_name::<TextView>("input_msg").unwrap();
+                view.set_content(format_error(input, &err));
+            }
+        }
+    });
+    cursive.add_layer(dialog);
+}
+
+/// Formats rolls for the log, with the rolls that were critical hits or
+/// fumbles in the tertiary color.
+pub fn format_rolls(rolls: &Rolls) -> StyledString {
+    let mut styled = StyledString::new();
+    // a group lists the totals on its first line, then one roll per line
+    let first_roll = if rolls.grouped { 1 } else { 0 };
+    for (i, line) in rolls.to_string().split('\n').enumerate() {
+        if i > 0 {
+            styled.append_plain("\n");
+        }
+        let result = i.checked_sub(first_roll).and_then(|i| rolls.results.get(i));
+        match result {
+            Some(result) if result.is_crit() || result.is_fumble() => {
+                styled.append_styled(line, PaletteColor::Tertiary)
+            }
+            _ => styled.append_plain(line),
+        }
+    }
+    styled
+}
+
+/// What expressions entered in the UI may refer to, as last sent by the
+/// controller.
+pub fn context(cursive: &mut Cursive) -> dice::Context {
+    cursive
+        .user_data::<dice::Context>()
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// How much work the preview may take, a small fraction of what the Odds
+/// dialog allows, as it is recalculated on every keystroke.
+const PREVIEW_WORK: usize = 200_000;
+
+/// Shown in the roll dialog until something is typed. A comparison right
+/// after a roll is one of its modifiers, while a spaced out one compares the
+/// total, which is easy to get wrong.
+const ROLL_HELP: &str = "2d6>=7 counts the dice of 7 or more,\n2d6 >= 7 compares the total with 7";
+
+/// Describes what rolling the input would give without rolling it: its
+/// range and average, or why it can't be rolled. Notes whether dice are
+/// counted as successes or explode, as that depends on spacing.
+fn format_preview(input: &str, context: &dice::Context) -> String {
+    if input.trim().is_empty() {
+        return ROLL_HELP.to_string();
+    }
+    let program = match dice::parse(input, context) {
+        Ok(program) => program,
+        Err(err) => return format_error(input, &err),
+    };
+    let rolls = program.rolls().count();
+    let preview = match dice::distribution_within(input, context, PREVIEW_WORK) {
+        Ok(distribution) => {
+            let each = if rolls > 1 {
+                format!("{} rolls, each ", rolls)
+            } else {
+                String::new()
             };
-        });
-        cursive.add_layer(dialog);
+            format!(
+                "{}{} to {}, average {:.1}",
+                each,
+                distribution.min(),
+                distribution.max(),
+                distribution.mean()
+            )
+        }
+        // the input can still be rolled, there is just no single range
+        Err(DiceError::MixedGroup { .. }) => format!("{} rolls", rolls),
+        Err(DiceError::TooComplex { .. }) => "Too complex to preview".to_string(),
+        Err(err) => return format_error(input, &err),
+    };
+
+    let any = |f: &dyn Fn(&Modifier) -> bool| program.exprs.iter().any(|e| e.has_modifier(f));
+    let mut notes = vec![];
+    if any(&|m| matches!(m, Modifier::Target(_) | Modifier::Failure(_))) {
+        notes.push("counting successes");
     }
+    if any(&|m| matches!(m, Modifier::Explode(..))) {
+        notes.push("exploding");
+    }
+    if notes.is_empty() {
+        preview
+    } else {
+        format!("{} ({})", preview, notes.join(", "))
+    }
+}
+
+/// Width of the longest bar in a histogram.
+const HISTOGRAM_WIDTH: usize = 20;
+
+/// Most rows in a histogram, beyond which outcomes are grouped into ranges.
+const HISTOGRAM_ROWS: usize = 100;
+
+/// Asks for an expression and shows its probability distribution.
+pub fn show_distribution_dialog(cursive: &mut Cursive) {
+    let dialog = ui::build_input_dialog("Odds", None, move |cursive, input| {
+        match dice::distribution(input, &context(cursive)) {
+            Ok(distribution) => {
+                cursive.pop_layer();
+                let histogram = format_histogram(&distribution);
+                let dialog = Dialog::around(TextView::new(histogram).scrollable())
+                    .title(input)
+                    .dismiss_button("Close");
+                cursive.add_layer(dialog);
+            }
+            Err(err) => {
+                let mut view = cursive.find_name::<TextView>("input_msg").unwrap();
+                view.set_content(format_error(input, &err));
+            }
+        };
+    });
+    cursive.add_layer(dialog);
+}
+
+/// Formats the distribution as a summary followed by one row per outcome,
+/// or per range of outcomes when there are too many, with the probability
+/// of rolling it and of rolling at least its lowest value.
+fn format_histogram(distribution: &Distribution) -> String {
+    let (min, max) = (distribution.min(), distribution.max());
+    let buckets = distribution.buckets(HISTOGRAM_ROWS);
+    let labels: Vec<String> = buckets
+        .iter()
+        .map(|b| {
+            if b.low == b.high {
+                b.low.to_string()
+            } else {
+                format!("{}..{}", b.low, b.high)
+            }
+        })
+        .collect();
+    let label_width = labels.iter().map(String::len).max().unwrap_or(0).max(5);
+
+    let mut lines = vec![
+        format!(
+            "Mean {:.2}, SD {:.2}, range {} to {}",
+            distribution.mean(),
+            distribution.std_dev(),
+            min,
+            max
+        ),
+        String::new(),
+        format!(
+            "{:>label_width$}  {:<width$}  {:>6}  {:>6}",
+            "",
+            "",
+            "=",
+            ">=",
+            label_width = label_width,
+            width = HISTOGRAM_WIDTH
+        ),
+    ];
+
+    let highest = buckets.iter().map(|b| b.probability).fold(0.0, f64::max);
+    for (bucket, label) in buckets.iter().zip(labels) {
+        let p = bucket.probability;
+        let bar = "#".repeat((p / highest * HISTOGRAM_WIDTH as f64).round() as usize);
+        lines.push(format!(
+            "{:>label_width$}  {:<width$}  {:>5.1}%  {:>5.1}%",
+            label,
+            bar,
+            p * 100.0,
+            bucket.at_least * 100.0,
+            label_width = label_width,
+            width = HISTOGRAM_WIDTH
+        ));
+    }
+
+    lines.join("\n")
+}
+
+/// Formats the error with a caret pointing at the offending position:
+///
+/// ```text
+/// 2d6 + $
+///       ^
+/// unexpected character '$'
+/// ```
+pub fn format_error(input: &str, err: &DiceError) -> String {
+    let column = input[..err.pos()].chars().count();
+    format!("{}\n{}^\n{}", input, " ".repeat(column), err)
 }
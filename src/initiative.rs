@@ -0,0 +1,160 @@
+//! Turn order for combat. Characters act in order of initiative, highest
+//! first, and the character whose turn it is is tracked in
+//! [`State::turn`], along with the round number.
+
+use crate::state::{Character, HpStatus, State};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+/// What initiative is rolled with unless another expression is given.
+pub const DEFAULT_INITIATIVE: &str = "1d20+@dex_mod";
+
+/// The default initiative roll for a character with these attributes, which
+/// is a plain `1d20` for characters without a `@dex_mod`.
+pub fn default_roll(attributes: &BTreeMap<String, i64>) -> &'static str {
+    if attributes.contains_key("dex_mod") {
+        DEFAULT_INITIATIVE
+    } else {
+        "1d20"
+    }
+}
+
+/// Sorts the characters by initiative, highest first. Ties go to the higher
+/// `@dex_mod`, then to whoever was listed first; characters without an
+/// initiative come last. The selection and the current turn stay with the
+/// characters they pointed at.
+pub fn sort(state: &mut State) {
+    let mut order: Vec<usize> = (0..state.characters.len()).collect();
+    // stable sort, so that the list order breaks the remaining ties
+    order.sort_by_key(|&i| sort_key(&state.characters[i]));
+
+    let position = |index: usize| order.iter().position(|&i| i == index).unwrap_or(index);
+    state.selected_index = position(state.selected_index);
+    state.turn = position(state.turn);
+
+    let mut characters: Vec<Option<Character>> = state.characters.drain(..).map(Some).collect();
+    state.characters = order.iter().filter_map(|&i| characters[i].take()).collect();
+}
+
+fn sort_key(character: &Character) -> (bool, Reverse<i64>, Reverse<i64>) {
+    let dex_mod = character.attributes.get("dex_mod").copied().unwrap_or(0);
+    (
+        character.initiative.is_none(),
+        Reverse(character.initiative.unwrap_or(0)),
+        Reverse(dex_mod),
+    )
+}
+
+/// Starts combat at the first character in the turn order.
+pub fn start(state: &mut State) {
+    sort(state);
+    state.round = 1;
+    state.turn = 0;
+    skip_dead(state);
+}
+
+/// Passes the turn to the next character that isn't dead, starting a new
+/// round after the last one. Starts combat if it hasn't started yet.
+pub fn next_turn(state: &mut State) {
+    if state.round == 0 {
+        return start(state);
+    }
+    advance(state);
+    skip_dead(state);
+}
+
+fn advance(state: &mut State) {
+    state.turn += 1;
+    if state.turn >= state.characters.len() {
+        state.turn = 0;
+        state.round += 1;
+    }
+}
+
+fn skip_dead(state: &mut State) {
+    for _ in 0..state.characters.len() {
+        match state.characters.get(state.turn) {
+            Some(c) if c.hp.status() == HpStatus::Dead => advance(state),
+            _ => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::build_state;
+
+    fn names(state: &State) -> Vec<&str> {
+        state.characters.iter().map(|c| c.name.as_str()).collect()
+    }
+
+    fn state(initiatives: &[(&str, Option<i64>, i64)]) -> State {
+        let mut state = build_state();
+        state.characters.truncate(initiatives.len());
+        for (c, &(name, initiative, dex_mod)) in state.characters.iter_mut().zip(initiatives) {
+            c.name = name.to_string();
+            c.initiative = initiative;
+            c.attributes.insert("dex_mod".to_string(), dex_mod);
+        }
+        state
+    }
+
+    #[test]
+    fn sort_by_initiative() {
+        let mut state = state(&[
+            ("Fighter", Some(12), 1),
+            ("Goblin", None, 2),
+            ("Rogue", Some(12), 4),
+            ("Wizard", Some(17), 0),
+            ("Cleric", Some(12), 1),
+        ]);
+        state.selected_index = 1;
+        sort(&mut state);
+        assert_eq!(
+            names(&state),
+            vec!["Wizard", "Rogue", "Fighter", "Cleric", "Goblin"]
+        );
+        assert_eq!(state.selected_index, 4);
+    }
+
+    #[test]
+    fn default_roll_without_dex_mod() {
+        let mut state = state(&[("Goblin", None, 2)]);
+        assert_eq!(
+            default_roll(&state.characters[0].attributes),
+            "1d20+@dex_mod"
+        );
+        state.characters[0].attributes.clear();
+        assert_eq!(default_roll(&state.characters[0].attributes), "1d20");
+    }
+
+    #[test]
+    fn turns_and_rounds() {
+        let mut state = state(&[("Goblin", Some(5), 0), ("Rogue", Some(15), 0)]);
+        next_turn(&mut state);
+        assert_eq!((state.round, state.turn), (1, 0));
+        assert_eq!(names(&state), vec!["Rogue", "Goblin"]);
+        next_turn(&mut state);
+        assert_eq!((state.round, state.turn), (1, 1));
+        next_turn(&mut state);
+        assert_eq!((state.round, state.turn), (2, 0));
+    }
+
+    #[test]
+    fn dead_characters_are_skipped() {
+        let mut state = state(&[
+            ("Rogue", Some(15), 0),
+            ("Goblin", Some(10), 0),
+            ("Orc", Some(5), 0),
+        ]);
+        state.characters[1].damage(100);
+        start(&mut state);
+        next_turn(&mut state);
+        assert_eq!((state.round, state.turn), (1, 2));
+
+        state.characters[0].damage(100);
+        next_turn(&mut state);
+        assert_eq!((state.round, state.turn), (2, 2));
+    }
+}
@@ -1,30 +1,442 @@
+mod campaign;
+mod condition;
 mod dice;
+mod initiative;
 mod state;
 mod ui;
 mod utils;
 
+use cursive::utils::markup::StyledString;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::path::PathBuf;
 use std::sync::mpsc;
 
+/// Where the campaign is kept unless `--campaign` is given.
+const DEFAULT_CAMPAIGN: &str = "campaign.txt";
+
 fn main() {
-    let state = state::build_state();
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(msg) => exit_with_error(&msg),
+    };
+    let seed = args.seed.unwrap_or_else(rand::random);
+
+    let mut state = state::build_state();
+    state.campaign = match campaign::load(&args.campaign) {
+        Ok(campaign) => campaign,
+        Err(msg) => exit_with_error(&msg),
+    };
+    let mut rng = StdRng::seed_from_u64(seed);
 
     let (tx, rx) = mpsc::channel::<ui::ControllerMessage>();
     let mut ui = ui::Ui::new(tx);
 
-    ui.display_state(state);
+    ui.display_state(&state);
+    ui.send(ui::UiMessage::SetContext(context(&state)));
+    log(
+        &mut ui,
+        &mut state,
+        format!("Session seed: {} (replay with --seed {})", seed, seed),
+    );
 
     while ui.step() {
         while let Some(msg) = rx.try_iter().next() {
             match msg {
-                ui::ControllerMessage::LogMessage(msg) => {
-                    // TODO write to app state
-                    ui.send(ui::UiMessage::Log(msg))
-                }
                 ui::ControllerMessage::AddNote(note) => {
-                    // TODO write to app state
-                    ui.send(ui::UiMessage::Log(note))
+                    let msg = match dice::eval_inline(&note, &context(&state), &mut rng) {
+                        Ok(text) => {
+                            // the text only shows totals, so the dice of each
+                            // inline roll follow on their own lines
+                            let mut msg = StyledString::plain(text.to_string());
+                            for rolls in text.rolls() {
+                                msg.append_plain("\n");
+                                msg.append(dice::ui::format_rolls(rolls));
+                            }
+                            msg
+                        }
+                        Err(err) => StyledString::plain(format!("{} (failed: {})", note, err)),
+                    };
+                    log(&mut ui, &mut state, msg)
+                }
+                ui::ControllerMessage::Roll(expr) => {
+                    match dice::eval(&expr, &context(&state), &mut rng) {
+                        Ok(rolls) => {
+                            let mut msg = StyledString::plain("Rolling: ");
+                            msg.append(dice::ui::format_rolls(&rolls));
+                            log(&mut ui, &mut state, msg);
+                            ui.send(ui::UiMessage::Rolled(expr));
+                            if rolls.results.iter().any(|r| r.is_crit()) {
+                                ui.send(ui::UiMessage::OfferCriticalDamage);
+                            }
+                        }
+                        Err(err) => {
+                            let msg = format!("Rolling: {} failed: {}", expr, err);
+                            log(&mut ui, &mut state, msg)
+                        }
+                    }
+                }
+                ui::ControllerMessage::Select(index) => {
+                    state.selected_index = index;
+                    ui.display_state(&state);
+                    ui.send(ui::UiMessage::SetContext(context(&state)));
+                }
+                ui::ControllerMessage::SetAttribute { name, value } => {
+                    if let Some(character) = state.characters.get_mut(state.selected_index) {
+                        let msg = match value {
+                            Some(value) => {
+                                character.attributes.insert(name.clone(), value);
+                                format!("{}: @{} = {}", character.name, name, value)
+                            }
+                            None => {
+                                character.attributes.remove(&name);
+                                format!("{}: removed @{}", character.name, name)
+                            }
+                        };
+                        log(&mut ui, &mut state, msg);
+                        ui.send(ui::UiMessage::SetContext(context(&state)));
+                    }
+                }
+                ui::ControllerMessage::Damage(expr) => {
+                    change_hp(&mut ui, &mut state, &mut rng, &expr, false)
+                }
+                ui::ControllerMessage::Heal(expr) => {
+                    change_hp(&mut ui, &mut state, &mut rng, &expr, true)
+                }
+                ui::ControllerMessage::AddCondition(condition) => {
+                    if let Some(character) = state.characters.get_mut(state.selected_index) {
+                        let mut msg = format!("{} is {}", character.name, condition);
+                        if let Some(source) = &condition.source {
+                            msg += &format!(" from {}", source);
+                        }
+                        character.conditions.push(condition);
+                        log(&mut ui, &mut state, msg);
+                        ui.display_state(&state);
+                    }
+                }
+                ui::ControllerMessage::RemoveCondition(name) => {
+                    if let Some(character) = state.characters.get_mut(state.selected_index) {
+                        let count = character.conditions.len();
+                        character.conditions.retain(|c| c.name != name);
+                        let msg = if character.conditions.len() < count {
+                            format!("{} is no longer {}", character.name, name)
+                        } else {
+                            format!("{} is not {}", character.name, name)
+                        };
+                        log(&mut ui, &mut state, msg);
+                        ui.display_state(&state);
+                    }
+                }
+                ui::ControllerMessage::SetInitiative(expr) => {
+                    let index = state.selected_index;
+                    roll_initiative(&mut ui, &mut state, &mut rng, index, &expr);
+                    initiative::sort(&mut state);
+                    ui.display_state(&state);
+                }
+                ui::ControllerMessage::RollInitiative => {
+                    for index in 0..state.characters.len() {
+                        roll_initiative(&mut ui, &mut state, &mut rng, index, "");
+                    }
+                    initiative::start(&mut state);
+                    announce_turn(&mut ui, &mut state);
+                }
+                ui::ControllerMessage::NextTurn => {
+                    if state.round > 0 {
+                        let turn = state.turn;
+                        tick_conditions(&mut ui, &mut state, turn, condition::TurnPoint::End);
+                    }
+                    initiative::next_turn(&mut state);
+                    announce_turn(&mut ui, &mut state);
+                }
+                ui::ControllerMessage::AddCharacter { name, max_hp } => {
+                    let name = state.add_character(&name, max_hp);
+                    log(
+                        &mut ui,
+                        &mut state,
+                        format!("Added {} ({} hp)", name, max_hp),
+                    );
+                    ui.display_state(&state);
+                }
+                ui::ControllerMessage::RenameCharacter(name) => {
+                    if let Some(character) = state.characters.get_mut(state.selected_index) {
+                        let msg = format!("Renamed {} to {}", character.name, name);
+                        character.name = name;
+                        log(&mut ui, &mut state, msg);
+                        ui.display_state(&state);
+                    }
+                }
+                ui::ControllerMessage::DuplicateCharacter => {
+                    if let Some(name) = state.duplicate_character(state.selected_index) {
+                        log(&mut ui, &mut state, format!("Added {}", name));
+                        ui.display_state(&state);
+                    }
                 }
+                ui::ControllerMessage::RemoveCharacter => {
+                    // the turn passes on if it was the removed character's
+                    let on_turn = state.round > 0 && state.selected_index == state.turn;
+                    if let Some(character) = state.remove_character(state.selected_index) {
+                        log(&mut ui, &mut state, format!("Removed {}", character.name));
+                        if on_turn {
+                            announce_turn(&mut ui, &mut state);
+                        }
+                        ui.display_state(&state);
+                        ui.send(ui::UiMessage::SetContext(context(&state)));
+                    }
+                }
+                ui::ControllerMessage::DefineMacro { name, expr } => {
+                    let msg = if expr.is_empty() {
+                        state.campaign.macros.remove(&name);
+                        format!("Removed macro #{}", name)
+                    } else {
+                        let msg = format!("Defined macro #{} = {}", name, expr);
+                        state.campaign.macros.insert(name, expr);
+                        msg
+                    };
+                    log(&mut ui, &mut state, msg);
+                    ui.send(ui::UiMessage::SetContext(context(&state)));
+                    if let Err(err) = campaign::save(&args.campaign, &state.campaign) {
+                        let msg = format!("Could not save {}: {}", args.campaign.display(), err);
+                        log(&mut ui, &mut state, msg);
+                    }
+                }
+            }
+        }
+    }
+
+    // leave the session log in the terminal, so that it can be kept
+    for msg in &state.log_messages {
+        println!("{}", msg);
+    }
+}
+
+/// Records a message in the session log and shows it in the UI.
+fn log(ui: &mut ui::Ui, state: &mut state::State, msg: impl Into<StyledString>) {
+    let msg = msg.into();
+    state.log_messages.push(msg.source().to_string());
+    ui.send(ui::UiMessage::Log(msg));
+}
+
+/// Damages or heals the selected character by the total of the expression,
+/// logging the rolls unless it is a plain number.
+fn change_hp(ui: &mut ui::Ui, state: &mut state::State, rng: &mut StdRng, expr: &str, heal: bool) {
+    let rolls = match dice::eval(expr, &context(state), rng) {
+        Ok(rolls) => rolls,
+        Err(err) => {
+            let msg = format!("Rolling: {} failed: {}", expr, err);
+            return log(ui, state, msg);
+        }
+    };
+    if expr.trim().parse::<i64>().is_err() {
+        let mut msg = StyledString::plain("Rolling: ");
+        msg.append(dice::ui::format_rolls(&rolls));
+        log(ui, state, msg);
+    }
+
+    let amount = rolls
+        .results
+        .iter()
+        .fold(0i64, |sum, r| sum.saturating_add(r.total))
+        .max(0);
+    let character = match state.characters.get_mut(state.selected_index) {
+        Some(character) => character,
+        None => return,
+    };
+    let before = current_hp(&character.hp);
+    let msg = if heal {
+        character.heal(amount);
+        format!(
+            "{} regains {} hit point{} ({} → {})",
+            character.name,
+            amount,
+            if amount == 1 { "" } else { "s" },
+            before,
+            current_hp(&character.hp)
+        )
+    } else {
+        character.damage(amount);
+        format!(
+            "{} takes {} damage ({} → {})",
+            character.name,
+            amount,
+            before,
+            current_hp(&character.hp)
+        )
+    };
+    log(ui, state, msg);
+    ui.display_state(state);
+}
+
+/// Sets the initiative of the character at `index` to the total of the
+/// expression, or of the default initiative roll if it is empty.
+fn roll_initiative(
+    ui: &mut ui::Ui,
+    state: &mut state::State,
+    rng: &mut StdRng,
+    index: usize,
+    expr: &str,
+) {
+    let (name, expr) = match state.characters.get(index) {
+        Some(character) if expr.trim().is_empty() => (
+            character.name.clone(),
+            initiative::default_roll(&character.attributes),
+        ),
+        Some(character) => (character.name.clone(), expr),
+        None => return,
+    };
+    let rolls = match dice::eval(expr, &character_context(state, index), rng) {
+        Ok(rolls) => rolls,
+        Err(err) => {
+            let msg = format!("{} initiative: {} failed: {}", name, expr, err);
+            return log(ui, state, msg);
+        }
+    };
+    let total = rolls.results.iter().map(|r| r.total).sum();
+    state.characters[index].initiative = Some(total);
+    let mut msg = StyledString::plain(format!("{} initiative: ", name));
+    if expr.trim().parse::<i64>().is_ok() {
+        msg.append_plain(total.to_string());
+    } else {
+        msg.append(dice::ui::format_rolls(&rolls));
+    }
+    log(ui, state, msg);
+}
+
+/// Counts down the conditions of the character at `index`, logging the ones
+/// that run out.
+fn tick_conditions(
+    ui: &mut ui::Ui,
+    state: &mut state::State,
+    index: usize,
+    at: condition::TurnPoint,
+) {
+    let messages = match state.characters.get_mut(index) {
+        Some(character) => condition::tick(character, at),
+        None => return,
+    };
+    for msg in messages {
+        log(ui, state, msg);
+    }
+}
+
+/// Selects the character whose turn it is and logs it, counting down the
+/// conditions that end at the start of the turn.
+fn announce_turn(ui: &mut ui::Ui, state: &mut state::State) {
+    let name = match state.characters.get(state.turn) {
+        Some(character) => character.name.clone(),
+        None => return,
+    };
+    state.selected_index = state.turn;
+    let msg = format!("Round {} — {}'s turn", state.round, name);
+    log(ui, state, msg);
+    tick_conditions(ui, state, state.turn, condition::TurnPoint::Start);
+    ui.display_state(state);
+    ui.send(ui::UiMessage::SetContext(context(state)));
+}
+
+/// The current hit points, with the temporary ones as in `15+5`.
+fn current_hp(hp: &state::Hp) -> String {
+    if hp.temp > 0 {
+        format!("{}+{}", hp.current, hp.temp)
+    } else {
+        hp.current.to_string()
+    }
+}
+
+/// What dice expressions may refer to by name.
+fn context(state: &state::State) -> dice::Context {
+    character_context(state, state.selected_index)
+}
+
+/// What dice expressions rolled for the character at `index` may refer to.
+fn character_context(state: &state::State, index: usize) -> dice::Context {
+    let variables = match state.characters.get(index) {
+        Some(character) => character.attributes.clone(),
+        None => Default::default(),
+    };
+    dice::Context {
+        macros: state.campaign.macros.clone(),
+        variables,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Args {
+    seed: Option<u64>,
+    campaign: PathBuf,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut seed = None;
+    let mut campaign = PathBuf::from(DEFAULT_CAMPAIGN);
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("missing value for {}", arg));
+        match arg.as_str() {
+            "--seed" => {
+                let value = value()?;
+                seed = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid seed: {}", value))?,
+                );
             }
+            "--campaign" => campaign = PathBuf::from(value()?),
+            _ => return Err(format!("unknown argument: {}", arg)),
         }
     }
+
+    Ok(Args { seed, campaign })
+}
+
+fn exit_with_error(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    eprintln!("usage: cursive-test [--seed <number>] [--campaign <file>]");
+    std::process::exit(1);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Args, String> {
+        super::parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(
+            parse_args(&[]),
+            Ok(Args {
+                seed: None,
+                campaign: PathBuf::from(DEFAULT_CAMPAIGN),
+            })
+        );
+    }
+
+    #[test]
+    fn seed_and_campaign() {
+        assert_eq!(
+            parse_args(&["--seed", "42", "--campaign", "dungeon.txt"]),
+            Ok(Args {
+                seed: Some(42),
+                campaign: PathBuf::from("dungeon.txt"),
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_args() {
+        assert_eq!(
+            parse_args(&["--seed", "lucky"]),
+            Err("invalid seed: lucky".to_string())
+        );
+        assert_eq!(
+            parse_args(&["--seed"]),
+            Err("missing value for --seed".to_string())
+        );
+        assert_eq!(
+            parse_args(&["--verbose"]),
+            Err("unknown argument: --verbose".to_string())
+        );
+    }
 }
@@ -1,39 +1,354 @@
+use crate::campaign::Campaign;
+use crate::condition::Condition;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The most hit points a character added at runtime may have.
+pub const MAX_HP: i64 = 1_000_000;
+
 #[derive(Default)]
 pub struct State {
+    pub campaign: Campaign,
     pub characters: Vec<Character>,
     pub selected_index: usize,
+    /// The combat round, counting from 1, or 0 outside of combat.
+    pub round: u32,
+    /// The index of the character whose turn it is during combat.
+    pub turn: usize,
     pub log_messages: Vec<String>,
 }
 
+impl State {
+    /// Adds a character at the end of the list, numbering its name if
+    /// another character has it already. Returns the name it was given.
+    pub fn add_character(&mut self, name: &str, max_hp: i64) -> String {
+        let name = if self.characters.iter().any(|c| c.name == name) {
+            self.numbered_name(name)
+        } else {
+            name.to_string()
+        };
+        self.characters.push(Character::new(&name, max_hp));
+        name
+    }
+
+    /// Adds a fresh copy of the character at `index` right after it, e.g.
+    /// `Goblin #3` after `Goblin #2`, with full hit points and no
+    /// conditions or initiative. Returns the name of the copy.
+    pub fn duplicate_character(&mut self, index: usize) -> Option<String> {
+        let original = self.characters.get(index)?;
+        let copy = Character {
+            name: self.numbered_name(&original.name),
+            hp: Hp::new(original.hp.max),
+            initiative: None,
+            conditions: vec![],
+            attributes: original.attributes.clone(),
+        };
+        let name = copy.name.clone();
+        self.characters.insert(index + 1, copy);
+        if self.selected_index > index {
+            self.selected_index += 1;
+        }
+        if self.turn > index {
+            self.turn += 1;
+        }
+        Some(name)
+    }
+
+    /// Removes the character at `index`. The selection and the current turn
+    /// stay with the characters they pointed at, or move on to the next one.
+    /// Removing the last character ends combat.
+    pub fn remove_character(&mut self, index: usize) -> Option<Character> {
+        if index >= self.characters.len() {
+            return None;
+        }
+        let character = self.characters.remove(index);
+        let last = self.characters.len().saturating_sub(1);
+        if self.selected_index > index {
+            self.selected_index -= 1;
+        }
+        self.selected_index = self.selected_index.min(last);
+        if self.turn > index {
+            self.turn -= 1;
+        } else if self.turn == index && self.turn > last {
+            // the last character in the order was removed on its turn
+            self.turn = 0;
+            if self.round > 0 {
+                self.round += 1;
+            }
+        }
+        if self.characters.is_empty() {
+            // combat is over without anyone left
+            self.round = 0;
+        }
+        Some(character)
+    }
+
+    /// The name numbered one higher than any character with the same name,
+    /// e.g. `Goblin #3` for `Goblin` or `Goblin #2` when there are `Goblin`
+    /// and `Goblin #2`. A name without a number counts as number 1.
+    fn numbered_name(&self, name: &str) -> String {
+        let base = match name.rfind(" #") {
+            Some(i) if name[i + 2..].parse::<u32>().is_ok() => &name[..i],
+            _ => name,
+        };
+        let highest = self
+            .characters
+            .iter()
+            .filter_map(|c| {
+                if c.name == base {
+                    Some(1)
+                } else {
+                    c.name
+                        .strip_prefix(base)?
+                        .strip_prefix(" #")?
+                        .parse::<u32>()
+                        .ok()
+                }
+            })
+            .max()
+            .unwrap_or(0);
+        format!("{} #{}", base, highest.saturating_add(1))
+    }
+}
+
 pub struct Character {
     pub name: String,
-    pub hp: String,
-    pub notes: Option<String>,
+    pub hp: Hp,
+    pub initiative: Option<i64>,
+    pub conditions: Vec<Condition>,
+    /// Values such as ability modifiers, referred to as `@name` in dice
+    /// expressions.
+    pub attributes: BTreeMap<String, i64>,
 }
 
 impl Character {
-    fn new(name: &str, hp: &str) -> Self {
+    fn new(name: &str, max_hp: i64) -> Self {
         Character {
             name: name.to_string(),
-            hp: hp.to_string(),
-            notes: None,
+            hp: Hp::new(max_hp),
+            initiative: None,
+            conditions: vec![],
+            attributes: BTreeMap::new(),
         }
     }
+
+    /// Takes damage, which is taken from the temporary hit points first.
+    /// Hit points may drop below 0, down to where the character is dead.
+    pub fn damage(&mut self, amount: i64) {
+        let absorbed = amount.min(self.hp.temp);
+        self.hp.temp -= absorbed;
+        self.hp.current = self
+            .hp
+            .current
+            .saturating_sub(amount - absorbed)
+            .max(-self.hp.max);
+    }
+
+    /// Regains hit points, counting up from 0 if below it, up to the
+    /// maximum.
+    pub fn heal(&mut self, amount: i64) {
+        self.hp.current = self
+            .hp
+            .current
+            .max(0)
+            .saturating_add(amount)
+            .min(self.hp.max);
+    }
+
+    /// Sets the temporary hit points. They don't add up, so the higher of
+    /// the old and new amount is kept.
+    pub fn set_temp_hp(&mut self, amount: i64) {
+        self.hp.temp = self.hp.temp.max(amount);
+    }
+}
+
+/// Hit points, where anything above `max` is temporary.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hp {
+    pub current: i64,
+    pub max: i64,
+    pub temp: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HpStatus {
+    Healthy,
+    /// At half of the maximum or below.
+    Bloodied,
+    /// At 0 or below.
+    Unconscious,
+    /// At the negative of the maximum, where the damage past 0 would have
+    /// taken all hit points again.
+    Dead,
+}
+
+impl Hp {
+    pub fn new(max: i64) -> Self {
+        Hp {
+            current: max,
+            max,
+            temp: 0,
+        }
+    }
+
+    pub fn status(&self) -> HpStatus {
+        if self.current <= -self.max {
+            HpStatus::Dead
+        } else if self.current <= 0 {
+            HpStatus::Unconscious
+        } else if self.current <= self.max / 2 {
+            HpStatus::Bloodied
+        } else {
+            HpStatus::Healthy
+        }
+    }
+}
+
+/// Formats the hit points as `15/24`, or `15/24+5` with temporary ones.
+impl fmt::Display for Hp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.current, self.max)?;
+        if self.temp > 0 {
+            write!(f, "+{}", self.temp)?;
+        }
+        Ok(())
+    }
 }
 
 pub fn build_state() -> State {
     let mut s = State::default();
     let mut characters = vec![
-        Character::new("Player #1", "24/24"),
-        Character::new("Player #2", "24/24"),
-        Character::new("Monster #1", "24/24"),
-        Character::new("Player #3", "24/24"),
-        Character::new("Monster #2", "24/24"),
-        Character::new("Player #4", "24/24"),
-        Character::new("Monster #3", "24/24"),
-        Character::new("Monster #4", "24/24"),
+        Character::new("Player #1", 24),
+        Character::new("Player #2", 24),
+        Character::new("Monster #1", 24),
+        Character::new("Player #3", 24),
+        Character::new("Monster #2", 24),
+        Character::new("Player #4", 24),
+        Character::new("Monster #3", 24),
+        Character::new("Monster #4", 24),
     ];
-    characters[2].notes = Some("dazed".to_string());
+    characters[2].conditions.push(Condition::new("dazed"));
+    characters[2].damage(14);
+    characters[1].set_temp_hp(5);
+    for c in &mut characters {
+        for (name, value) in &[("str_mod", 3), ("dex_mod", 2), ("prof", 2)] {
+            c.attributes.insert(name.to_string(), *value);
+        }
+    }
     s.characters.extend(characters);
     s
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn names(state: &State) -> Vec<&str> {
+        state.characters.iter().map(|c| c.name.as_str()).collect()
+    }
+
+    #[test]
+    fn add_characters() {
+        let mut state = State::default();
+        assert_eq!(state.add_character("Goblin", 7), "Goblin");
+        assert_eq!(state.add_character("Goblin", 7), "Goblin #2");
+        assert_eq!(state.add_character("Goblin #2", 7), "Goblin #3");
+        assert_eq!(state.add_character("Orc", 15), "Orc");
+        assert_eq!(state.characters[3].hp, Hp::new(15));
+    }
+
+    #[test]
+    fn duplicate_characters() {
+        let mut state = build_state();
+        state.selected_index = 5;
+        state.turn = 2;
+        assert_eq!(state.duplicate_character(2), Some("Monster #5".to_string()));
+        assert_eq!(state.characters[3].name, "Monster #5");
+        assert_eq!(state.characters[3].hp.current, 24);
+        assert!(state.characters[3].conditions.is_empty());
+        assert_eq!(state.characters[3].attributes["str_mod"], 3);
+        assert_eq!((state.selected_index, state.turn), (6, 2));
+        assert_eq!(state.duplicate_character(100), None);
+
+        state.characters[0].name = "Goblin #2147483647".to_string();
+        assert_eq!(
+            state.duplicate_character(0),
+            Some("Goblin #2147483648".to_string())
+        );
+        state.characters[0].name = format!("Goblin #{}", u32::MAX);
+        assert_eq!(
+            state.duplicate_character(0),
+            Some(format!("Goblin #{}", u32::MAX))
+        );
+    }
+
+    #[test]
+    fn remove_characters() {
+        let mut state = build_state();
+        state.characters.truncate(3);
+        state.selected_index = 2;
+        state.turn = 1;
+        state.round = 1;
+
+        assert_eq!(state.remove_character(0).unwrap().name, "Player #1");
+        assert_eq!(names(&state), vec!["Player #2", "Monster #1"]);
+        assert_eq!((state.selected_index, state.turn), (1, 0));
+
+        state.turn = 1;
+        state.remove_character(1);
+        assert_eq!((state.selected_index, state.turn, state.round), (0, 0, 2));
+        assert!(state.remove_character(1).is_none());
+
+        state.remove_character(0);
+        state.remove_character(0);
+        assert!(state.characters.is_empty());
+        assert_eq!((state.selected_index, state.turn, state.round), (0, 0, 0));
+    }
+
+    #[test]
+    fn damage() {
+        let mut c = Character::new("Goblin", 24);
+        c.damage(9);
+        assert_eq!(c.hp.current, 15);
+        assert_eq!(c.hp.status(), HpStatus::Healthy);
+        c.damage(3);
+        assert_eq!(c.hp.status(), HpStatus::Bloodied);
+        c.damage(12);
+        assert_eq!(c.hp.current, 0);
+        assert_eq!(c.hp.status(), HpStatus::Unconscious);
+        c.damage(100);
+        assert_eq!(c.hp.current, -24);
+        assert_eq!(c.hp.status(), HpStatus::Dead);
+
+        let mut c = Character::new("Tarrasque", i64::MAX);
+        assert_eq!(c.hp.status(), HpStatus::Healthy);
+        c.damage(i64::MAX / 2 + 1);
+        assert_eq!(c.hp.status(), HpStatus::Bloodied);
+    }
+
+    #[test]
+    fn temporary_hp() {
+        let mut c = Character::new("Fighter", 24);
+        c.set_temp_hp(5);
+        c.set_temp_hp(3);
+        assert_eq!(c.hp.to_string(), "24/24+5");
+        c.damage(3);
+        assert_eq!(c.hp.to_string(), "24/24+2");
+        c.damage(7);
+        assert_eq!(c.hp.to_string(), "19/24");
+    }
+
+    #[test]
+    fn heal() {
+        let mut c = Character::new("Cleric", 24);
+        c.damage(30);
+        c.heal(4);
+        assert_eq!(c.hp.current, 4);
+        c.heal(100);
+        assert_eq!(c.hp.current, 24);
+        c.heal(i64::MAX);
+        assert_eq!(c.hp.current, 24);
+        c.damage(i64::MAX);
+        assert_eq!(c.hp.current, -24);
+    }
+}
@@ -1,12 +1,22 @@
-use crate::dice::ui::RollDiceDialog;
+use crate::condition::{self, Condition};
+use crate::dice;
+use crate::dice::ui::{
+    context, format_error, show_critical_damage_dialog, show_define_macro_dialog,
+    show_distribution_dialog, show_macros_dialog, RollDiceDialog,
+};
+use crate::initiative;
 use crate::state;
+use cursive::event::{EventResult, Key};
 use cursive::theme::*;
 use cursive::traits::*;
+use cursive::utils::markup::StyledString;
 use cursive::utils::span::SpannedString;
 use cursive::view::*;
 use cursive::views::*;
 use cursive::Cursive;
 use enumset::EnumSet;
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::sync::mpsc;
 
 pub struct Ui {
@@ -14,25 +24,71 @@ pub struct Ui {
     ui_rx: mpsc::Receiver<UiMessage>,
     ui_tx: mpsc::Sender<UiMessage>,
     controller_tx: mpsc::Sender<ControllerMessage>,
+    roll_dialog: RollDiceDialog,
 }
 
 pub enum UiMessage {
-    Log(String),
+    Log(StyledString),
+    /// Records an expression that was rolled, to be recalled or rolled
+    /// again from the roll dialog.
+    Rolled(String),
+    /// Replaces what dice expressions entered in the UI may refer to.
+    SetContext(dice::Context),
+    /// Offers to roll damage for a critical hit that was just rolled.
+    OfferCriticalDamage,
 }
 
 pub enum ControllerMessage {
-    LogMessage(String),
     AddNote(String),
+    Roll(String),
+    /// Selects the character at the index.
+    Select(usize),
+    /// Sets an attribute of the selected character, or removes it.
+    SetAttribute {
+        name: String,
+        value: Option<i64>,
+    },
+    /// Damages the selected character by an amount or dice expression.
+    Damage(String),
+    /// Heals the selected character by an amount or dice expression.
+    Heal(String),
+    /// Adds a condition to the selected character.
+    AddCondition(Condition),
+    /// Removes the condition with the name from the selected character.
+    RemoveCondition(String),
+    /// Sets the initiative of the selected character to an amount or the
+    /// total of a dice expression, or rolls the default if it is empty.
+    SetInitiative(String),
+    /// Rolls initiative for every character and starts combat.
+    RollInitiative,
+    /// Passes the turn to the next character in the initiative order.
+    NextTurn,
+    AddCharacter {
+        name: String,
+        max_hp: i64,
+    },
+    /// Renames the selected character.
+    RenameCharacter(String),
+    /// Adds a copy of the selected character after it.
+    DuplicateCharacter,
+    RemoveCharacter,
+    /// Adds or replaces a macro, or removes it if the expression is empty.
+    DefineMacro {
+        name: String,
+        expr: String,
+    },
 }
 
 impl Ui {
     pub fn new(controller_tx: mpsc::Sender<ControllerMessage>) -> Self {
         let (ui_tx, ui_rx) = mpsc::channel::<UiMessage>();
+        let roll_dialog = RollDiceDialog::new(&controller_tx);
         let mut ui = Ui {
             cursive: cursive::default(),
             ui_rx,
             ui_tx,
             controller_tx,
+            roll_dialog: roll_dialog.clone(),
         };
 
         ui.cursive.load_toml(include_str!("style.toml")).unwrap();
@@ -41,18 +97,96 @@ impl Ui {
             cursive.quit();
         });
 
-        let tx = ui.controller_tx.clone();
+        let dialog = roll_dialog.clone();
         ui.cursive.add_global_callback('r', move |cursive| {
-            let dialog = RollDiceDialog::new(&tx);
             dialog.show(cursive);
         });
 
+        ui.cursive.add_global_callback('R', move |cursive| {
+            roll_dialog.reroll_last(cursive);
+        });
+
+        ui.cursive.add_global_callback('p', move |cursive| {
+            show_distribution_dialog(cursive);
+        });
+
+        let tx = ui.controller_tx.clone();
+        ui.cursive.add_global_callback('m', move |cursive| {
+            show_macros_dialog(cursive, &tx);
+        });
+
+        let tx = ui.controller_tx.clone();
+        ui.cursive.add_global_callback('M', move |cursive| {
+            show_define_macro_dialog(cursive, &tx);
+        });
+
         let tx = ui.controller_tx.clone();
         ui.cursive.add_global_callback('N', move |cursive| {
             show_notes_dialog(cursive, &tx);
         });
 
-        let root = build_root();
+        let tx = ui.controller_tx.clone();
+        ui.cursive.add_global_callback('@', move |cursive| {
+            show_attribute_dialog(cursive, &tx);
+        });
+
+        let tx = ui.controller_tx.clone();
+        ui.cursive.add_global_callback('d', move |cursive| {
+            show_hp_dialog(cursive, &tx, "Damage", ControllerMessage::Damage);
+        });
+
+        let tx = ui.controller_tx.clone();
+        ui.cursive.add_global_callback('h', move |cursive| {
+            show_hp_dialog(cursive, &tx, "Heal", ControllerMessage::Heal);
+        });
+
+        let tx = ui.controller_tx.clone();
+        ui.cursive.add_global_callback('c', move |cursive| {
+            show_add_condition_dialog(cursive, &tx);
+        });
+
+        let tx = ui.controller_tx.clone();
+        ui.cursive.add_global_callback('C', move |cursive| {
+            show_remove_condition_dialog(cursive, &tx);
+        });
+
+        let tx = ui.controller_tx.clone();
+        ui.cursive.add_global_callback('i', move |cursive| {
+            show_initiative_dialog(cursive, &tx);
+        });
+
+        let tx = ui.controller_tx.clone();
+        ui.cursive.add_global_callback('I', move |_| {
+            tx.send(ControllerMessage::RollInitiative).unwrap();
+        });
+
+        let tx = ui.controller_tx.clone();
+        ui.cursive.add_global_callback('n', move |_| {
+            tx.send(ControllerMessage::NextTurn).unwrap();
+        });
+
+        let tx = ui.controller_tx.clone();
+        ui.cursive.add_global_callback('a', move |cursive| {
+            show_add_character_dialog(cursive, &tx);
+        });
+
+        let tx = ui.controller_tx.clone();
+        ui.cursive.add_global_callback('e', move |cursive| {
+            show_rename_dialog(cursive, &tx);
+        });
+
+        let tx = ui.controller_tx.clone();
+        ui.cursive.add_global_callback('D', move |_| {
+            tx.send(ControllerMessage::DuplicateCharacter).unwrap();
+        });
+
+        let tx = ui.controller_tx.clone();
+        ui.cursive.add_global_callback('X', move |cursive| {
+            show_remove_character_dialog(cursive, &tx);
+        });
+
+        let tx = ui.controller_tx.clone();
+        let root = build_root(tx);
         ui.cursive.add_layer(root);
         ui
     }
@@ -65,6 +199,11 @@ impl Ui {
         while let Some(message) = self.ui_rx.try_iter().next() {
             match message {
                 UiMessage::Log(msg) => self.add_log_msg(msg),
+                UiMessage::Rolled(input) => self.roll_dialog.record(&input),
+                UiMessage::SetContext(context) => self.cursive.set_user_data(context),
+                UiMessage::OfferCriticalDamage => {
+                    show_critical_damage_dialog(&mut self.cursive, &self.controller_tx)
+                }
             }
         }
 
@@ -75,31 +214,47 @@ impl Ui {
     }
 
     pub fn send(&mut self, msg: UiMessage) {
-        let _ = self.ui_tx.send(msg).unwrap();
+        self.ui_tx.send(msg).unwrap();
     }
 
-    pub fn display_state(&mut self, state: state::State) {
+    pub fn display_state(&mut self, state: &state::State) {
+        // custom colors of the theme have no PaletteColor of their own
+        let bloodied = self
+            .cursive
+            .current_theme()
+            .palette
+            .custom("bloodied")
+            .map_or_else(Style::default, |&color| color.into());
         let mut view = self
             .cursive
             .find_name::<SelectView<String>>("player_list")
             .unwrap();
-        draw_character_list(&mut view, &state)
+        draw_character_list(&mut view, state, bloodied)
     }
 
-    fn add_log_msg(&mut self, msg: String) {
+    fn add_log_msg(&mut self, msg: StyledString) {
         self.cursive.call_on_name("log", |view: &mut ListView| {
             view.add_child("", TextView::new(msg));
         });
     }
 }
 
-fn build_root() -> impl View {
+fn build_root(tx: mpsc::Sender<ControllerMessage>) -> impl View {
     let encounter_list = LinearLayout::vertical().child(TextView::new("> Goblin ambush"));
     let encounter_panel =
         Panel::new(encounter_list.resized(SizeConstraint::Full, SizeConstraint::Full))
             .title("Encounters");
 
-    let player_list = SelectView::<String>::new();
+    let player_list = SelectView::<String>::new().on_select(move |cursive, _| {
+        let index = cursive
+            .call_on_name("player_list", |view: &mut SelectView<String>| {
+                view.selected_id()
+            })
+            .flatten();
+        if let Some(index) = index {
+            tx.send(ControllerMessage::Select(index)).unwrap();
+        }
+    });
     let player_panel = Panel::new(
         player_list
             .with_name("player_list")
@@ -121,21 +276,251 @@ fn build_root() -> impl View {
     LinearLayout::horizontal().child(panel1).child(panel2)
 }
 
+/// Asks for a note for the log, which may contain inline rolls such as
+/// `[[2d6+1]]`. The controller rolls them when the note is added.
 fn show_notes_dialog(cursive: &mut Cursive, tx: &mpsc::Sender<ControllerMessage>) {
     let tx = tx.clone();
-    let dialog = build_input_dialog("Notes", None, move |cursive, text| {
-        tx.send(ControllerMessage::AddNote(text.to_string()))
+    let dialog = build_input_dialog(
+        "Notes",
+        None,
+        move |cursive, text| match dice::parse_inline(text, &context(cursive)) {
+            Ok(()) => {
+                tx.send(ControllerMessage::AddNote(text.to_string()))
+                    .unwrap();
+                cursive.pop_layer();
+            }
+            Err(err) => {
+                let mut view = cursive.find_name::<TextView>("input_msg").unwrap();
+                view.set_content(format_error(&err.expr, &err.error));
+            }
+        },
+    );
+    cursive.add_layer(dialog);
+}
+
+/// Asks for an attribute of the selected character, such as `str_mod = 3`.
+/// An empty value removes the attribute.
+fn show_attribute_dialog(cursive: &mut Cursive, tx: &mpsc::Sender<ControllerMessage>) {
+    let tx = tx.clone();
+    let message = Some("name = value".to_string());
+    let dialog = build_input_dialog("Set attribute", message, move |cursive, input| {
+        let attribute = dice::parse_definition(input, '@').and_then(|(name, value)| {
+            if value.is_empty() {
+                return Ok((name, None));
+            }
+            match value.parse() {
+                Ok(value) => Ok((name, Some(value))),
+                Err(_) => Err(format!("'{}' is not a whole number", value)),
+            }
+        });
+        match attribute {
+            Ok((name, value)) => {
+                tx.send(ControllerMessage::SetAttribute { name, value })
+                    .unwrap();
+                cursive.pop_layer();
+            }
+            Err(msg) => {
+                let mut view = cursive.find_name::<TextView>("input_msg").unwrap();
+                view.set_content(msg);
+            }
+        }
+    });
+    cursive.add_layer(dialog);
+}
+
+/// Asks for an amount or dice expression, such as `9` or `2d6+3`, to damage
+/// or heal the selected character by.
+fn show_hp_dialog(
+    cursive: &mut Cursive,
+    tx: &mpsc::Sender<ControllerMessage>,
+    title: &str,
+    message: fn(String) -> ControllerMessage,
+) {
+    let tx = tx.clone();
+    let dialog = build_input_dialog(title, None, move |cursive, input| {
+        match dice::parse(input, &context(cursive)) {
+            Ok(_) => {
+                tx.send(message(input.to_string())).unwrap();
+                cursive.pop_layer();
+            }
+            Err(err) => {
+                let mut view = cursive.find_name::<TextView>("input_msg").unwrap();
+                view.set_content(format_error(input, &err));
+            }
+        }
+    });
+    cursive.add_layer(dialog);
+}
+
+/// Asks for the name and maximum hit points of a new character, such as
+/// `Goblin 7`.
+fn show_add_character_dialog(cursive: &mut Cursive, tx: &mpsc::Sender<ControllerMessage>) {
+    let tx = tx.clone();
+    let message = Some("name hp".to_string());
+    let dialog = build_input_dialog("Add character", message, move |cursive, input| {
+        let input = input.trim();
+        let character = input
+            .rfind(' ')
+            .map(|i| (input[..i].trim(), input[i + 1..].parse()))
+            .filter(|(name, _)| !name.is_empty());
+        match character {
+            Some((name, Ok(max_hp))) if (1..=state::MAX_HP).contains(&max_hp) => {
+                let name = name.to_string();
+                tx.send(ControllerMessage::AddCharacter { name, max_hp })
+                    .unwrap();
+                cursive.pop_layer();
+            }
+            Some((_, Ok(_))) => {
+                let mut view = cursive.find_name::<TextView>("input_msg").unwrap();
+                view.set_content(format!("hit points must be from 1 to {}", state::MAX_HP));
+            }
+            _ => {
+                let mut view = cursive.find_name::<TextView>("input_msg").unwrap();
+                view.set_content("expected a name and hit points, e.g. 'Goblin 7'");
+            }
+        }
+    });
+    cursive.add_layer(dialog);
+}
+
+/// Asks for a new name for the selected character.
+fn show_rename_dialog(cursive: &mut Cursive, tx: &mpsc::Sender<ControllerMessage>) {
+    let tx = tx.clone();
+    let dialog = build_input_dialog("Rename", None, move |cursive, input| {
+        let name = input.trim();
+        if name.is_empty() {
+            let mut view = cursive.find_name::<TextView>("input_msg").unwrap();
+            view.set_content("expected a name");
+            return;
+        }
+        tx.send(ControllerMessage::RenameCharacter(name.to_string()))
             .unwrap();
         cursive.pop_layer();
     });
     cursive.add_layer(dialog);
 }
 
+/// Asks whether to remove the selected character.
+fn show_remove_character_dialog(cursive: &mut Cursive, tx: &mpsc::Sender<ControllerMessage>) {
+    let tx = tx.clone();
+    let dialog = Dialog::text("Remove the selected character?")
+        .button("Remove", move |cursive| {
+            tx.send(ControllerMessage::RemoveCharacter).unwrap();
+            cursive.pop_layer();
+        })
+        .dismiss_button("Cancel");
+    cursive.add_layer(dialog);
+}
+
+/// Asks for a condition of the selected character, such as
+/// `dazed 1 save from Wizard`.
+fn show_add_condition_dialog(cursive: &mut Cursive, tx: &mpsc::Sender<ControllerMessage>) {
+    let tx = tx.clone();
+    let message = Some("name [rounds] [start|end] [save] [from source]".to_string());
+    let dialog =
+        build_input_dialog(
+            "Add condition",
+            message,
+            move |cursive, input| match condition::parse(input) {
+                Ok(condition) => {
+                    tx.send(ControllerMessage::AddCondition(condition)).unwrap();
+                    cursive.pop_layer();
+                }
+                Err(msg) => {
+                    let mut view = cursive.find_name::<TextView>("input_msg").unwrap();
+                    view.set_content(msg);
+                }
+            },
+        );
+    cursive.add_layer(dialog);
+}
+
+/// Asks for the name of a condition to remove from the selected character.
+fn show_remove_condition_dialog(cursive: &mut Cursive, tx: &mpsc::Sender<ControllerMessage>) {
+    let tx = tx.clone();
+    let dialog = build_input_dialog("Remove condition", None, move |cursive, input| {
+        tx.send(ControllerMessage::RemoveCondition(input.trim().to_string()))
+            .unwrap();
+        cursive.pop_layer();
+    });
+    cursive.add_layer(dialog);
+}
+
+/// Asks for the initiative of the selected character, as an amount or a
+/// dice expression to roll.
+fn show_initiative_dialog(cursive: &mut Cursive, tx: &mpsc::Sender<ControllerMessage>) {
+    let tx = tx.clone();
+    let default = initiative::default_roll(&context(cursive).variables);
+    let message = Some(format!("Leave empty to roll {}", default));
+    let dialog = build_input_dialog("Initiative", message, move |cursive, input| {
+        let parsed = if input.trim().is_empty() {
+            Ok(())
+        } else {
+            dice::parse(input, &context(cursive)).map(drop)
+        };
+        match parsed {
+            Ok(()) => {
+                tx.send(ControllerMessage::SetInitiative(input.to_string()))
+                    .unwrap();
+                cursive.pop_layer();
+            }
+            Err(err) => {
+                let mut view = cursive.find_name::<TextView>("input_msg").unwrap();
+                view.set_content(format_error(input, &err));
+            }
+        }
+    });
+    cursive.add_layer(dialog);
+}
+
 pub fn build_input_dialog<F>(
     title: impl Into<String>,
     message: Option<String>,
     on_submit: F,
 ) -> impl View
+where
+    F: 'static + Clone + Fn(&mut Cursive, &str),
+{
+    build_history_input_dialog(title, message, vec![], on_submit)
+}
+
+/// Earlier inputs of a dialog, cycled through with Up and Down.
+struct History {
+    entries: Vec<String>,
+    /// The entry shown in the input field, or `entries.len()` for a new
+    /// input.
+    shown: usize,
+}
+
+impl History {
+    fn new(entries: Vec<String>) -> Self {
+        let shown = entries.len();
+        History { entries, shown }
+    }
+
+    /// Moves to the previous entry, or to the next one if not `up`, and
+    /// returns what the input field should show: an empty input after the
+    /// last entry. Returns `None` when there is nothing further.
+    fn recall(&mut self, up: bool) -> Option<&str> {
+        self.shown = if up {
+            self.shown.checked_sub(1)?
+        } else if self.shown < self.entries.len() {
+            self.shown + 1
+        } else {
+            return None;
+        };
+        Some(self.entries.get(self.shown).map_or("", String::as_str))
+    }
+}
+
+/// Like [`build_input_dialog`], but Up and Down in the input field cycle
+/// through the earlier inputs in `history`, oldest first.
+pub fn build_history_input_dialog<F>(
+    title: impl Into<String>,
+    message: Option<String>,
+    history: Vec<String>,
+    on_submit: F,
+) -> impl View
 where
     F: 'static + Clone + Fn(&mut Cursive, &str),
 {
@@ -148,7 +533,18 @@ where
             front: ColorType::Palette(PaletteColor::Highlight),
             back: ColorType::Palette(PaletteColor::Primary),
         })
-        .with_name("input_field")
+        .with_name("input_field");
+
+    let history = Rc::new(RefCell::new(History::new(history)));
+    let recall = move |view: &mut NamedView<EditView>, up: bool| {
+        let input = history.borrow_mut().recall(up)?.to_string();
+        let callback = view.get_mut().set_content(input);
+        Some(EventResult::Consumed(Some(callback)))
+    };
+    let recall_down = recall.clone();
+    let input_field = OnEventView::new(input_field)
+        .on_pre_event_inner(Key::Up, move |view, _| recall(view, true))
+        .on_pre_event_inner(Key::Down, move |view, _| recall_down(view, false))
         .full_width();
 
     let mut content = LinearLayout::vertical().child(input_field);
@@ -170,21 +566,33 @@ where
         .max_width(40)
 }
 
-fn draw_character_list(view: &mut SelectView<String>, state: &state::State) {
+fn draw_character_list(view: &mut SelectView<String>, state: &state::State, bloodied: Style) {
+    view.clear();
     let longest_name = state
         .characters
         .iter()
         .map(|c| c.name.chars().count())
         .max()
         .unwrap_or(0);
+    let any_initiative = state.characters.iter().any(|c| c.initiative.is_some());
     for (i, c) in state.characters.iter().enumerate() {
         let name_length = c.name.chars().count();
         let padding = longest_name - name_length + 2;
-        let dots = std::iter::repeat(".").take(padding).collect::<String>();
-        let notes = c.notes.clone().unwrap_or("".to_string());
-        let selection = if i == state.selected_index { ">" } else { " " };
+        let dots = ".".repeat(padding);
+        let conditions: Vec<String> = c.conditions.iter().map(|c| c.to_string()).collect();
+        // during combat, the marker follows the turn instead of the selection
+        let current = if state.round > 0 {
+            state.turn
+        } else {
+            state.selected_index
+        };
+        let selection = if i == current { ">" } else { " " };
 
         let mut span = SpannedString::styled(selection, Style::default());
+        if any_initiative {
+            let initiative = c.initiative.map_or(String::new(), |n| n.to_string());
+            span.append_plain(format!("{:>3} ", initiative));
+        }
         let name_span = SpannedString::styled(
             &c.name,
             Style {
@@ -195,8 +603,17 @@ fn draw_character_list(view: &mut SelectView<String>, state: &state::State) {
                 }),
             },
         );
-        let rest_span =
-            SpannedString::styled(format!("{}{} {}", dots, c.hp, notes), Style::default());
+        let hp_style = match c.hp.status() {
+            state::HpStatus::Healthy => Style::default(),
+            state::HpStatus::Bloodied => bloodied,
+            state::HpStatus::Unconscious => PaletteColor::Tertiary.into(),
+            state::HpStatus::Dead => {
+                Style::from(PaletteColor::Secondary).combine(Effect::Strikethrough)
+            }
+        };
+        let mut rest_span = SpannedString::styled(dots, Style::default());
+        rest_span.append_styled(c.hp.to_string(), hp_style);
+        rest_span.append_plain(format!(" {}", conditions.join(", ")));
         span.append(name_span);
         span.append(rest_span);
         view.add_item(span, "".to_string());
@@ -204,3 +621,27 @@ fn draw_character_list(view: &mut SelectView<String>, state: &state::State) {
         view.set_selection(state.selected_index);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recall_history() {
+        let mut history = History::new(vec!["1d20".to_string(), "2d6".to_string()]);
+        assert_eq!(history.recall(false), None);
+        assert_eq!(history.recall(true), Some("2d6"));
+        assert_eq!(history.recall(true), Some("1d20"));
+        assert_eq!(history.recall(true), None);
+        assert_eq!(history.recall(false), Some("2d6"));
+        assert_eq!(history.recall(false), Some(""));
+        assert_eq!(history.recall(false), None);
+    }
+
+    #[test]
+    fn recall_empty_history() {
+        let mut history = History::new(vec![]);
+        assert_eq!(history.recall(true), None);
+        assert_eq!(history.recall(false), None);
+    }
+}